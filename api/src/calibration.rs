@@ -0,0 +1,142 @@
+use std::{env, fs};
+
+use eyre::Result;
+use serde::Deserialize;
+
+use crate::{
+    lexical_index,
+    types::{
+        bundle::Bundle,
+        source::{Chunks, Source},
+        user::User,
+    },
+    vector_index,
+};
+
+// share of answerable questions calibration may turn away as not found
+const MAX_MISS_RATE: f32 = 0.02;
+
+lazy_static! {
+    /// Threshold for accounts that haven't been calibrated, `NOT_FOUND_THRESHOLD`.
+    /// Unset means every question goes to the model.
+    pub static ref NOT_FOUND_THRESHOLD: Option<f32> = env::var("NOT_FOUND_THRESHOLD")
+        .ok()
+        .and_then(|value| value.parse().ok());
+
+    /// BM25 score of the best chunk from which a question always goes to the model,
+    /// `NOT_FOUND_LEXICAL_SCORE`. Exact terms like skus match lexically while their vectors don't.
+    pub static ref NOT_FOUND_LEXICAL_SCORE: f32 = env::var("NOT_FOUND_LEXICAL_SCORE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(5.0);
+}
+
+/// A question asked of an account, labelled with whether its sources can answer it.
+#[derive(Debug, Deserialize)]
+pub struct Sample {
+    pub query: String,
+    pub answerable: bool,
+}
+
+/// Similarity of the closest chunk of the sources to the query, and whether a chunk matches
+/// it lexically well enough that /message never turns it away as not found.
+pub async fn measure(user_id: u64, sources: &[Source], query: &str) -> Result<Option<(f32, bool)>> {
+    let query_embedding = Chunks::query(query.to_string()).await?;
+    let lexical_match = lexical_index::top_matching(sources, query, 1)
+        .iter()
+        .any(|(_, score)| *score >= *NOT_FOUND_LEXICAL_SCORE);
+    Ok(
+        vector_index::top_similar(user_id, sources, &query_embedding, 1)
+            .first()
+            .map(|(_, similarity)| (*similarity, lexical_match)),
+    )
+}
+
+/// The highest threshold that turns away at most `max_miss_rate` of the answerable samples,
+/// None without answerable samples to calibrate against. Lexical matches are never turned
+/// away, so they're left out of the samples.
+pub fn threshold(samples: &[(f32, bool)], max_miss_rate: f32) -> Option<f32> {
+    let mut answerable = samples
+        .iter()
+        .filter(|(_, answerable)| *answerable)
+        .map(|(similarity, _)| *similarity)
+        .collect::<Vec<_>>();
+    answerable.sort_by(f32::total_cmp);
+
+    let allowed_misses = (answerable.len() as f32 * max_miss_rate).floor() as usize;
+    answerable.get(allowed_misses).copied()
+}
+
+/// `pagebot calibrate <user_id> <samples.json>`: measures the labelled questions against the
+/// account's sources and saves the highest threshold that loses at most `MAX_MISS_RATE` of the
+/// answerable ones. Counts follow /message, which skips the threshold on a lexical match.
+pub async fn run(user_id: u64, samples_path: &str) -> Result<()> {
+    let mut user = User::by_id(user_id)?.ok_or_else(|| eyre::eyre!("User not found"))?;
+    let samples: Vec<Sample> = serde_json::from_slice(&fs::read(samples_path)?)?;
+
+    let mut sources = Source::by_user(user_id)?;
+    sources.extend(Bundle::sources(user_id)?);
+    // /message only searches sources embedded by the current model
+    sources.retain(|source| source.chunks.is_current());
+
+    let mut measured = vec![];
+    for sample in samples {
+        if let Some((similarity, lexical_match)) = measure(user_id, &sources, &sample.query).await?
+        {
+            measured.push((similarity, lexical_match, sample.answerable));
+        }
+    }
+
+    let decided = measured
+        .iter()
+        .filter(|(_, lexical_match, _)| !lexical_match)
+        .map(|(similarity, _, answerable)| (*similarity, *answerable))
+        .collect::<Vec<_>>();
+    let threshold = threshold(&decided, MAX_MISS_RATE)
+        .ok_or_else(|| eyre::eyre!("No answerable samples to calibrate with"))?;
+    let turned_away = |answerable: bool| {
+        decided
+            .iter()
+            .filter(|(similarity, is_answerable)| {
+                *is_answerable == answerable && *similarity < threshold
+            })
+            .count()
+    };
+    println!(
+        "threshold {:.4}: {}/{} off-topic questions short-circuited, {}/{} answerable ones lost",
+        threshold,
+        turned_away(false),
+        measured
+            .iter()
+            .filter(|(_, _, answerable)| !answerable)
+            .count(),
+        turned_away(true),
+        measured
+            .iter()
+            .filter(|(_, _, answerable)| *answerable)
+            .count(),
+    );
+
+    user.not_found_threshold = Some(threshold);
+    user.save()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_keeps_answerable() {
+        let mut samples = (0..100)
+            .map(|i| (0.5 + i as f32 * 0.004, true))
+            .collect::<Vec<_>>();
+        samples.extend([(0.1, false), (0.2, false), (0.55, false)]);
+
+        // two answerable questions may be lost
+        let calibrated = threshold(&samples, 0.02).expect("threshold");
+        assert!((calibrated - 0.508).abs() < 1e-6);
+        assert_eq!(threshold(&samples, 0.0), Some(0.5));
+        assert_eq!(threshold(&[(0.1, false)], 0.02), None);
+    }
+}
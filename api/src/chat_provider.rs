@@ -0,0 +1,644 @@
+use std::{
+    env,
+    fmt::{Debug, Formatter},
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+};
+
+use async_openai::{
+    config::{AzureConfig, Config, OpenAIConfig},
+    types::{
+        ChatCompletionFunctions, ChatCompletionRequestMessage, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs, Role,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use eyre::Result;
+use futures::{Stream, StreamExt};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto, types::user::User};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub bot: bool,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatFunction {
+    pub name: String,
+    pub description: String,
+    // json schema of the arguments
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub max_tokens: u16,
+    pub temperature: Option<f32>,
+    // offered to the model when the provider supports function calling
+    pub functions: Vec<ChatFunction>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatReply {
+    Content(String),
+    FunctionCall { name: String, arguments: String },
+}
+
+/// Content deltas of a streamed reply.
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<String>> + Send>>;
+
+/// Completes chats for the bot, one per configured backend.
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn complete(&self, request: ChatRequest) -> Result<ChatReply>;
+    async fn complete_stream(&self, request: ChatRequest) -> Result<ChatStream>;
+    /// Whether `ChatRequest::functions` are passed on, otherwise they're ignored.
+    fn supports_functions(&self) -> bool;
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChatModel {
+    pub name: String,
+    pub context_window: usize,
+}
+
+impl ChatModel {
+    fn new(name: &str, context_window: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            context_window,
+        }
+    }
+}
+
+/// The backend an account's messages are answered with, stored on the user.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    // whatever the server is configured with, see `ProviderConfig::from_env`
+    #[default]
+    Server,
+    OpenAi,
+    Azure {
+        api_base: String,
+        deployment_id: String,
+        api_version: String,
+    },
+    // llama.cpp server, vLLM, Ollama and other servers implementing /chat/completions
+    Compatible {
+        api_base: String,
+        // most local models don't follow function definitions reliably
+        #[serde(default)]
+        functions: bool,
+    },
+}
+
+/// An api key stored encrypted.
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct ApiKey {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Debug for ApiKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ApiKey(<redacted>)")
+    }
+}
+
+impl ApiKey {
+    pub fn new(api_key: &str) -> Result<Self> {
+        let (nonce, ciphertext) = crypto::encrypt(api_key.as_bytes())?;
+        Ok(Self { nonce, ciphertext })
+    }
+
+    pub fn decrypt(&self) -> Result<String> {
+        Ok(String::from_utf8(crypto::decrypt(
+            &self.nonce,
+            &self.ciphertext,
+        )?)?)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct ChatSettings {
+    #[serde(default)]
+    pub provider: ProviderConfig,
+    // smallest window first, the server's models when empty
+    #[serde(default)]
+    pub models: Vec<ChatModel>,
+    #[serde(default)]
+    pub api_key: Option<ApiKey>,
+}
+
+/// Only tells whether an api key is stored, never its value.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ChatSettingsOutput {
+    pub provider: ProviderConfig,
+    pub models: Vec<ChatModel>,
+    pub api_key: bool,
+}
+
+impl From<ChatSettings> for ChatSettingsOutput {
+    fn from(settings: ChatSettings) -> Self {
+        Self {
+            provider: settings.provider,
+            models: settings.models,
+            api_key: settings.api_key.is_some(),
+        }
+    }
+}
+
+lazy_static! {
+    /// Provider of accounts on `ProviderConfig::Server`, with its api key.
+    static ref SERVER_PROVIDER: (ProviderConfig, String) = ProviderConfig::from_env();
+
+    /// Models of accounts that don't configure their own, up to `CHAT_MODEL`.
+    pub static ref SERVER_MODELS: Vec<ChatModel> = {
+        let models = vec![
+            ChatModel::new("gpt-3.5-turbo", 4096),
+            ChatModel::new("gpt-3.5-turbo-16k", 16384),
+        ];
+        match env::var("CHAT_MODEL")
+            .ok()
+            .and_then(|name| models.iter().position(|model| model.name == name))
+        {
+            Some(position) => models[..=position].to_vec(),
+            None => models,
+        }
+    };
+
+    /// Hosts accounts may point their api base at even though they are internal,
+    /// comma separated in `CHAT_API_BASE_ALLOWLIST`.
+    static ref API_BASE_ALLOWLIST: Vec<String> = env::var("CHAT_API_BASE_ALLOWLIST")
+        .map(|hosts| {
+            hosts
+                .split(',')
+                .map(|host| host.trim().to_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+}
+
+impl ProviderConfig {
+    /// Reads `CHAT_PROVIDER` (openai, azure or compatible), the key from `OPENAI_API_KEY`
+    /// (falling back to the `.env` value baked in at build time) and, depending on the
+    /// provider, `CHAT_API_BASE`, `AZURE_DEPLOYMENT_ID`, `AZURE_API_VERSION` and `CHAT_FUNCTIONS`.
+    pub fn from_env() -> (Self, String) {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+        let config = match var("CHAT_PROVIDER").as_deref().unwrap_or("openai") {
+            "azure" => ProviderConfig::Azure {
+                api_base: var("CHAT_API_BASE").unwrap_or_default(),
+                deployment_id: var("AZURE_DEPLOYMENT_ID").unwrap_or_default(),
+                api_version: var("AZURE_API_VERSION")
+                    .unwrap_or_else(|| "2023-07-01-preview".to_string()),
+            },
+            "compatible" => ProviderConfig::Compatible {
+                api_base: var("CHAT_API_BASE")
+                    .unwrap_or_else(|| "http://localhost:8080/v1".to_string()),
+                functions: var("CHAT_FUNCTIONS").map_or(false, |value| value == "true"),
+            },
+            _ => ProviderConfig::OpenAi,
+        };
+        let api_key =
+            var("OPENAI_API_KEY").unwrap_or_else(|| dotenv!("OPENAI_API_KEY").to_string());
+        (config, api_key)
+    }
+
+    /// The url requests go to, for providers an account points somewhere itself.
+    pub fn api_base(&self) -> Option<&str> {
+        match self {
+            ProviderConfig::Azure { api_base, .. }
+            | ProviderConfig::Compatible { api_base, .. } => Some(api_base),
+            ProviderConfig::Server | ProviderConfig::OpenAi => None,
+        }
+    }
+
+    /// Whether requests fail without the account's own api key.
+    pub fn needs_api_key(&self) -> bool {
+        matches!(self, ProviderConfig::OpenAi | ProviderConfig::Azure { .. })
+    }
+
+    /// Accounts' hosts are called through `account_http_client`, the server's as configured.
+    pub fn provider(&self, api_key: &str) -> Result<Arc<dyn ChatProvider>> {
+        match self {
+            ProviderConfig::Server => Ok(SERVER_PROVIDER
+                .0
+                .build(&SERVER_PROVIDER.1, reqwest::Client::new())),
+            _ => Ok(self.build(api_key, account_http_client(self.api_base())?)),
+        }
+    }
+
+    fn build(&self, api_key: &str, http_client: reqwest::Client) -> Arc<dyn ChatProvider> {
+        match self {
+            ProviderConfig::Server | ProviderConfig::OpenAi => Arc::new(OpenAiProvider::new(
+                OpenAIConfig::new().with_api_key(api_key),
+                http_client,
+                true,
+            )),
+            ProviderConfig::Azure {
+                api_base,
+                deployment_id,
+                api_version,
+            } => Arc::new(OpenAiProvider::new(
+                AzureConfig::new()
+                    .with_api_base(api_base)
+                    .with_deployment_id(deployment_id)
+                    .with_api_version(api_version)
+                    .with_api_key(api_key),
+                http_client,
+                true,
+            )),
+            ProviderConfig::Compatible {
+                api_base,
+                functions,
+            } => Arc::new(OpenAiProvider::new(
+                OpenAIConfig::new()
+                    .with_api_base(api_base.trim_end_matches('/'))
+                    .with_api_key(api_key),
+                http_client,
+                *functions,
+            )),
+        }
+    }
+}
+
+/// Loopback, private, link-local and other addresses that aren't on the public internet.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // carrier-grade nat, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local fc00::/7 and link-local fe80::/10
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// The lowercased host of an http(s) url, without the brackets of ipv6 addresses.
+fn api_base_host(api_base: &str) -> Option<String> {
+    let url = match reqwest::Url::parse(api_base) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => return None,
+    };
+    url.host_str().map(|host| {
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_lowercase()
+    })
+}
+
+/// Whether an account may send its chats to this url: http(s) to a host that only resolves
+/// to public addresses, unless the operator allowed the host.
+pub async fn is_allowed_api_base(api_base: &str) -> bool {
+    let host = match api_base_host(api_base) {
+        Some(host) => host,
+        None => return false,
+    };
+    if API_BASE_ALLOWLIST.contains(&host) {
+        return true;
+    }
+
+    let addresses = match tokio::net::lookup_host((host.as_str(), 0)).await {
+        Ok(addresses) => addresses.collect::<Vec<_>>(),
+        Err(_) => return false,
+    };
+    !addresses.is_empty() && addresses.iter().all(|address| !is_internal(address.ip()))
+}
+
+/// Resolves like the system but fails for hosts with an internal address. It runs on every
+/// connection, so a host can't be pointed inward after the account's settings were checked.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(resolve_public(name))
+    }
+}
+
+async fn resolve_public(name: Name) -> Result<Addrs, Box<dyn std::error::Error + Send + Sync>> {
+    let addresses = tokio::net::lookup_host((name.as_str(), 0))
+        .await?
+        .collect::<Vec<_>>();
+    if addresses.is_empty() || addresses.iter().any(|address| is_internal(address.ip())) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} resolves to an internal address", name.as_str()),
+        )));
+    }
+    Ok(Box::new(addresses.into_iter()))
+}
+
+/// Client for the hosts accounts configure. Names go through `PublicResolver` and redirects
+/// aren't followed, ip addresses are checked here since they aren't resolved. Hosts the
+/// operator allowed are called as is.
+fn account_http_client(api_base: Option<&str>) -> Result<reqwest::Client> {
+    let builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    let host = match api_base {
+        Some(api_base) => {
+            Some(api_base_host(api_base).ok_or_else(|| eyre::eyre!("Invalid api base"))?)
+        }
+        None => None,
+    };
+
+    match host {
+        Some(host) if API_BASE_ALLOWLIST.contains(&host) => Ok(builder.build()?),
+        Some(host) if host.parse::<IpAddr>().map_or(false, is_internal) => {
+            Err(eyre::eyre!("Api base {} is an internal address", host))
+        }
+        _ => Ok(builder.dns_resolver(Arc::new(PublicResolver)).build()?),
+    }
+}
+
+/// A provider with the models requests may pick from.
+#[derive(Clone)]
+pub struct Chat {
+    pub provider: Arc<dyn ChatProvider>,
+    // smallest window first, never empty
+    pub models: Vec<ChatModel>,
+}
+
+impl Chat {
+    pub fn new(provider: Arc<dyn ChatProvider>, mut models: Vec<ChatModel>) -> Self {
+        if models.is_empty() {
+            models = SERVER_MODELS.clone();
+        }
+        models.sort_by_key(|model| model.context_window);
+        Self { provider, models }
+    }
+
+    pub fn for_user(user: &User) -> Result<Self> {
+        let settings = &user.chat;
+        let api_key = match &settings.api_key {
+            Some(api_key) => api_key.decrypt()?,
+            None => String::new(),
+        };
+        Ok(Self::new(
+            settings.provider.provider(&api_key)?,
+            settings.models.clone(),
+        ))
+    }
+
+    pub fn smallest(&self) -> &ChatModel {
+        &self.models[0]
+    }
+
+    /// The model whose window sizes the context budget.
+    pub fn largest(&self) -> &ChatModel {
+        &self.models[self.models.len() - 1]
+    }
+
+    /// The smallest model with room for `tokens`, prompt and answer, the largest otherwise.
+    pub fn model_for(&self, tokens: usize) -> &ChatModel {
+        self.models
+            .iter()
+            .find(|model| tokens <= model.context_window)
+            .unwrap_or_else(|| self.largest())
+    }
+}
+
+/// OpenAI's chat api, which Azure and the OpenAI-compatible servers share.
+pub struct OpenAiProvider<C: Config> {
+    client: Client<C>,
+    functions: bool,
+}
+
+impl<C: Config> OpenAiProvider<C> {
+    pub fn new(config: C, http_client: reqwest::Client, functions: bool) -> Self {
+        Self {
+            client: Client::with_config(config).with_http_client(http_client),
+            functions,
+        }
+    }
+
+    fn request(&self, request: ChatRequest, stream: bool) -> Result<CreateChatCompletionRequest> {
+        let messages = request
+            .messages
+            .into_iter()
+            .map(|message| ChatCompletionRequestMessage {
+                content: message.content.into(),
+                name: None,
+                role: match message.bot {
+                    true => Role::Assistant,
+                    false => Role::User,
+                },
+                function_call: None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.model(request.model)
+            .messages(messages)
+            .max_tokens(request.max_tokens)
+            .stream(stream);
+        if let Some(temperature) = request.temperature {
+            args.temperature(temperature);
+        }
+        if self.functions && !request.functions.is_empty() {
+            let functions = request
+                .functions
+                .into_iter()
+                .map(|function| ChatCompletionFunctions {
+                    name: function.name,
+                    description: Some(function.description),
+                    parameters: Some(function.parameters),
+                })
+                .collect::<Vec<_>>();
+            args.functions(functions).function_call("auto");
+        }
+        Ok(args.build()?)
+    }
+}
+
+#[async_trait]
+impl<C: Config + Send + Sync + 'static> ChatProvider for OpenAiProvider<C> {
+    async fn complete(&self, request: ChatRequest) -> Result<ChatReply> {
+        let response = self
+            .client
+            .chat()
+            .create(self.request(request, false)?)
+            .await?;
+        let message = response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
+            .ok_or_else(|| eyre::eyre!("Chat completion has no choices"))?;
+
+        Ok(match message.function_call {
+            Some(function_call) => ChatReply::FunctionCall {
+                name: function_call.name,
+                arguments: function_call.arguments,
+            },
+            None => ChatReply::Content(message.content.unwrap_or_default()),
+        })
+    }
+
+    async fn complete_stream(&self, request: ChatRequest) -> Result<ChatStream> {
+        let stream = self
+            .client
+            .chat()
+            .create_stream(self.request(request, true)?)
+            .await?;
+
+        Ok(Box::pin(stream.map(|response| -> Result<String> {
+            Ok(response?
+                .choices
+                .first()
+                .and_then(|choice| choice.delta.content.clone())
+                .unwrap_or_default())
+        })))
+    }
+
+    fn supports_functions(&self) -> bool {
+        self.functions
+    }
+}
+
+/// Replies with what the test queued, in order, and keeps the requests it was sent.
+#[cfg(test)]
+pub struct ScriptedProvider {
+    functions: bool,
+    replies: std::sync::Mutex<std::collections::VecDeque<ChatReply>>,
+    streams: std::sync::Mutex<std::collections::VecDeque<Vec<String>>>,
+    pub requests: std::sync::Mutex<Vec<ChatRequest>>,
+}
+
+#[cfg(test)]
+impl ScriptedProvider {
+    pub fn new(functions: bool) -> Self {
+        Self {
+            functions,
+            replies: Default::default(),
+            streams: Default::default(),
+            requests: Default::default(),
+        }
+    }
+
+    pub fn reply(self, reply: ChatReply) -> Self {
+        self.replies.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn stream(self, deltas: &[&str]) -> Self {
+        self.streams
+            .lock()
+            .unwrap()
+            .push_back(deltas.iter().map(|delta| delta.to_string()).collect());
+        self
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ChatProvider for ScriptedProvider {
+    async fn complete(&self, request: ChatRequest) -> Result<ChatReply> {
+        self.requests.lock().unwrap().push(request);
+        self.replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| eyre::eyre!("No scripted reply left"))
+    }
+
+    async fn complete_stream(&self, request: ChatRequest) -> Result<ChatStream> {
+        self.requests.lock().unwrap().push(request);
+        let deltas = self
+            .streams
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| eyre::eyre!("No scripted stream left"))?;
+        Ok(Box::pin(futures::stream::iter(deltas.into_iter().map(Ok))))
+    }
+
+    fn supports_functions(&self) -> bool {
+        self.functions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_smallest_model_that_fits() {
+        let chat = Chat::new(
+            Arc::new(ScriptedProvider::new(false)),
+            vec![
+                ChatModel::new("large", 32768),
+                ChatModel::new("small", 8192),
+            ],
+        );
+
+        assert_eq!(chat.smallest().name, "small");
+        assert_eq!(chat.model_for(8000).name, "small");
+        assert_eq!(chat.model_for(9000).name, "large");
+        assert_eq!(chat.model_for(40000).name, "large");
+    }
+
+    #[test]
+    fn settings_without_secrets() {
+        let settings: ChatSettings = serde_json::from_value(serde_json::json!({
+            "provider": { "type": "compatible", "api_base": "http://localhost:11434/v1" },
+            "models": [{ "name": "llama3", "context_window": 8192 }],
+        }))
+        .expect("settings");
+
+        assert_eq!(
+            settings.provider,
+            ProviderConfig::Compatible {
+                api_base: "http://localhost:11434/v1".to_string(),
+                functions: false,
+            }
+        );
+        assert!(!ChatSettingsOutput::from(settings).api_key);
+    }
+
+    #[tokio::test]
+    async fn internal_api_bases() {
+        for api_base in [
+            "http://localhost:11434/v1",
+            "http://127.0.0.1:8080/v1",
+            "http://10.0.0.5/v1",
+            "http://192.168.1.20/v1",
+            "http://169.254.169.254/latest",
+            "http://[::1]:8080/v1",
+            "http://[fd00::1]/v1",
+            "http://[::ffff:127.0.0.1]/v1",
+            "file:///etc/passwd",
+        ] {
+            assert!(!is_allowed_api_base(api_base).await, "{}", api_base);
+        }
+        assert!(is_allowed_api_base("https://1.1.1.1/v1").await);
+        assert!(account_http_client(Some("http://169.254.169.254/latest")).is_err());
+        assert!(account_http_client(Some("http://[::1]:8080/v1")).is_err());
+        assert!(account_http_client(Some("https://1.1.1.1/v1")).is_ok());
+        assert!(!is_internal("100.128.0.1".parse().expect("ip")));
+        assert!(is_internal("100.64.0.1".parse().expect("ip")));
+    }
+}
@@ -0,0 +1,52 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use eyre::Result;
+
+lazy_static! {
+    // 32 byte key, hex encoded
+    static ref CIPHER: Aes256Gcm = {
+        let key = decode_hex(dotenv!("CREDENTIALS_KEY")).expect("Invalid CREDENTIALS_KEY");
+        assert_eq!(key.len(), 32, "CREDENTIALS_KEY must be 32 bytes");
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+    };
+}
+
+/// Returns (nonce, ciphertext).
+pub fn encrypt(plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = CIPHER
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| eyre::eyre!("Failed to encrypt"))?;
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+pub fn decrypt(nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    CIPHER
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| eyre::eyre!("Failed to decrypt"))
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| eyre::eyre!("Invalid hex"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_hex("00ff10").unwrap(), vec![0, 255, 16]);
+        assert!(decode_hex("0g").is_err());
+        assert!(decode_hex("abc").is_err());
+    }
+}
@@ -1,16 +1,28 @@
-use crate::types::source::Source;
+use crate::types::bundle::Bundle;
+use crate::types::credential::StoredCredential;
+use crate::types::pinned_answer::PinnedAnswer;
+use crate::types::source::{LinkedSource, Source};
 use crate::types::{usage::Usage, user::User};
+use crate::vector_index::IndexManifest;
 use eyre::Result;
 use heed::byteorder::BE;
-use heed::types::{OwnedType, SerdeJson, Str, U64};
+use heed::types::{ByteSlice, DecodeIgnore, OwnedType, SerdeJson, Str, U64};
+use source_codec::SourceCodec;
 use std::fs;
 use std::path::Path;
 
+pub mod source_codec;
+
 pub struct _DB {
     pub env: heed::Env,
     pub user_db: heed::Database<UserId, SerdeJson<User>>,
     pub usage_db: heed::Database<UsageId, SerdeJson<Usage>>,
-    pub source_cache_db: heed::Database<SourceCacheId, SerdeJson<Source>>,
+    pub source_cache_db: heed::Database<SourceCacheId, SourceCodec>,
+    pub user_sources_db: heed::Database<UserId, SerdeJson<Vec<LinkedSource>>>,
+    pub pinned_answer_db: heed::Database<UserId, SerdeJson<Vec<PinnedAnswer>>>,
+    pub bundle_db: heed::Database<BundleId, SerdeJson<Bundle>>,
+    pub credential_db: heed::Database<UserId, SerdeJson<Vec<StoredCredential>>>,
+    pub vector_index_db: heed::Database<UserId, SerdeJson<IndexManifest>>,
 }
 
 impl Default for _DB {
@@ -23,6 +35,7 @@ type BEU64 = U64<BE>;
 type UserId = OwnedType<BEU64>;
 type UsageId = OwnedType<BEU64>;
 type SourceCacheId = Str;
+type BundleId = Str;
 
 impl _DB {
     pub fn new() -> Self {
@@ -30,7 +43,7 @@ impl _DB {
         fs::create_dir_all(&path).expect("Failed to create db directory");
         let env = heed::EnvOpenOptions::new()
             .map_size(40 * 1024 * 1024 * 1024) // 40 GB
-            .max_dbs(7)
+            .max_dbs(8)
             .open(path)
             .expect("Failed to open db");
 
@@ -46,11 +59,36 @@ impl _DB {
             .create_database(Some("source_cache"))
             .expect("Failed to create source_cache db");
 
+        let user_sources_db = env
+            .create_database(Some("user_sources"))
+            .expect("Failed to create user_sources db");
+
+        let pinned_answer_db = env
+            .create_database(Some("pinned_answer"))
+            .expect("Failed to create pinned_answer db");
+
+        let bundle_db = env
+            .create_database(Some("bundle"))
+            .expect("Failed to create bundle db");
+
+        let credential_db = env
+            .create_database(Some("credential"))
+            .expect("Failed to create credential db");
+
+        let vector_index_db = env
+            .create_database(Some("vector_index"))
+            .expect("Failed to create vector_index db");
+
         Self {
             env,
             user_db,
             usage_db,
             source_cache_db,
+            user_sources_db,
+            pinned_answer_db,
+            bundle_db,
+            credential_db,
+            vector_index_db,
         }
     }
 
@@ -142,6 +180,305 @@ impl _DB {
 
         Ok(source_cache)
     }
+
+    pub fn source_cache_uris(&self) -> Result<Vec<String>> {
+        let rtxn = self.create_rtxn()?;
+        let uris = self
+            .source_cache_db
+            // only the keys are needed
+            .remap_data_type::<DecodeIgnore>()
+            .iter(&rtxn)
+            .map_err(|e| eyre::eyre!("Failed to get source_cache: {:?}", e))?
+            .filter_map(|entry| entry.ok().map(|(uri, _)| uri.to_string()))
+            .collect();
+
+        Ok(uris)
+    }
+
+    /// Rewrites the sources still stored as json in the binary format, a batch per transaction.
+    pub fn source_cache_migrate(&self) -> Result<usize> {
+        let json_uris = {
+            let rtxn = self.create_rtxn()?;
+            self.source_cache_db
+                .remap_data_type::<ByteSlice>()
+                .iter(&rtxn)
+                .map_err(|e| eyre::eyre!("Failed to get source_cache: {:?}", e))?
+                .filter_map(|entry| entry.ok())
+                .filter(|(_, bytes)| source_codec::is_json(bytes))
+                .map(|(uri, _)| uri.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        for uris in json_uris.chunks(100) {
+            let mut wtxn = self.create_wtxn()?;
+            for uri in uris {
+                let source = self
+                    .source_cache_db
+                    .get(&wtxn, uri)
+                    .map_err(|e| eyre::eyre!("Failed to get source_cache: {:?}", e))?;
+                if let Some(source) = source {
+                    self.source_cache_db
+                        .put(&mut wtxn, uri, &source)
+                        .map_err(|e| eyre::eyre!("Failed to save source_cache: {:?}", e))?;
+                }
+            }
+            wtxn.commit()
+                .map_err(|e| eyre::eyre!("Failed to commit source_cache: {:?}", e))?;
+        }
+
+        Ok(json_uris.len())
+    }
+
+    /// Reads, changes and writes the account's links in one transaction, so concurrent
+    /// updates can't drop each other's links. Only writes when `update` reports a change.
+    pub fn user_sources_update(
+        &self,
+        user_id: u64,
+        update: impl FnOnce(&mut Vec<LinkedSource>) -> bool,
+    ) -> Result<Vec<LinkedSource>> {
+        let mut wtxn = self.create_wtxn()?;
+        let user_id = &BEU64::new(user_id);
+        let mut links = self
+            .user_sources_db
+            .get(&wtxn, user_id)
+            .map_err(|e| eyre::eyre!("Failed to get user_sources: {:?}", e))?
+            .unwrap_or_default();
+        if !update(&mut links) {
+            return Ok(links);
+        }
+
+        self.user_sources_db
+            .put(&mut wtxn, user_id, &links)
+            .map_err(|e| eyre::eyre!("Failed to save user_sources: {:?}", e))?;
+
+        wtxn.commit()
+            .map(|_| links)
+            .map_err(|e| eyre::eyre!("Failed to commit user_sources: {:?}", e))
+    }
+
+    pub fn user_sources(&self, user_id: u64) -> Result<Vec<LinkedSource>> {
+        let rtxn = self.create_rtxn()?;
+        let user_id = &BEU64::new(user_id);
+        let links = self
+            .user_sources_db
+            .get(&rtxn, user_id)
+            .map_err(|e| eyre::eyre!("Failed to get user_sources: {:?}", e))?;
+
+        Ok(links.unwrap_or_default())
+    }
+
+    /// Drops the uri from the account's sources and, in the same transaction, the cached
+    /// source itself once no other account links it. Returns whether the cache entry went.
+    pub fn user_sources_unlink(&self, user_id: u64, uri: &str) -> Result<bool> {
+        let mut wtxn = self.create_wtxn()?;
+        let key = &BEU64::new(user_id);
+        let mut links = self
+            .user_sources_db
+            .get(&wtxn, key)
+            .map_err(|e| eyre::eyre!("Failed to get user_sources: {:?}", e))?
+            .unwrap_or_default();
+        links.retain(|owned| owned.uri != uri);
+        self.user_sources_db
+            .put(&mut wtxn, key, &links)
+            .map_err(|e| eyre::eyre!("Failed to save user_sources: {:?}", e))?;
+
+        let linked = self
+            .user_sources_db
+            .iter(&wtxn)
+            .map_err(|e| eyre::eyre!("Failed to get user_sources: {:?}", e))?
+            .filter_map(|entry| entry.ok())
+            .any(|(_, links)| links.iter().any(|owned| owned.uri == uri));
+
+        if !linked {
+            self.source_cache_db
+                .delete(&mut wtxn, uri)
+                .map_err(|e| eyre::eyre!("Failed to delete source_cache: {:?}", e))?;
+        }
+
+        wtxn.commit()
+            .map(|_| !linked)
+            .map_err(|e| eyre::eyre!("Failed to commit user_sources: {:?}", e))
+    }
+
+    /// Reads, changes and writes the account's pinned answers in one transaction, so
+    /// concurrent saves can't drop each other's. Only writes when `update` reports a change.
+    pub fn pinned_answers_update(
+        &self,
+        user_id: u64,
+        update: impl FnOnce(&mut Vec<PinnedAnswer>) -> bool,
+    ) -> Result<Vec<PinnedAnswer>> {
+        let mut wtxn = self.create_wtxn()?;
+        let user_id = &BEU64::new(user_id);
+        let mut pinned_answers = self
+            .pinned_answer_db
+            .get(&wtxn, user_id)
+            .map_err(|e| eyre::eyre!("Failed to get pinned_answers: {:?}", e))?
+            .unwrap_or_default();
+        if !update(&mut pinned_answers) {
+            return Ok(pinned_answers);
+        }
+
+        self.pinned_answer_db
+            .put(&mut wtxn, user_id, &pinned_answers)
+            .map_err(|e| eyre::eyre!("Failed to save pinned_answers: {:?}", e))?;
+
+        wtxn.commit()
+            .map(|_| pinned_answers)
+            .map_err(|e| eyre::eyre!("Failed to commit pinned_answers: {:?}", e))
+    }
+
+    pub fn pinned_answers(&self, user_id: u64) -> Result<Vec<PinnedAnswer>> {
+        let rtxn = self.create_rtxn()?;
+        let user_id = &BEU64::new(user_id);
+        let pinned_answers = self
+            .pinned_answer_db
+            .get(&rtxn, user_id)
+            .map_err(|e| eyre::eyre!("Failed to get pinned_answers: {:?}", e))?;
+
+        Ok(pinned_answers.unwrap_or_default())
+    }
+
+    pub fn pinned_answer_user_ids(&self) -> Result<Vec<u64>> {
+        let rtxn = self.create_rtxn()?;
+        let user_ids = self
+            .pinned_answer_db
+            .remap_data_type::<DecodeIgnore>()
+            .iter(&rtxn)
+            .map_err(|e| eyre::eyre!("Failed to get pinned_answers: {:?}", e))?
+            .filter_map(|entry| entry.ok().map(|(user_id, _)| user_id.get()))
+            .collect();
+
+        Ok(user_ids)
+    }
+
+    pub fn credentials_save(
+        &self,
+        user_id: u64,
+        credentials: Vec<StoredCredential>,
+    ) -> Result<Vec<StoredCredential>> {
+        let mut wtxn = self.create_wtxn()?;
+        let user_id = &BEU64::new(user_id);
+        self.credential_db
+            .put(&mut wtxn, user_id, &credentials)
+            .map_err(|e| eyre::eyre!("Failed to save credentials: {:?}", e))?;
+
+        wtxn.commit()
+            .map(|_| credentials)
+            .map_err(|e| eyre::eyre!("Failed to commit credentials: {:?}", e))
+    }
+
+    pub fn credentials(&self, user_id: u64) -> Result<Vec<StoredCredential>> {
+        let rtxn = self.create_rtxn()?;
+        let user_id = &BEU64::new(user_id);
+        let credentials = self
+            .credential_db
+            .get(&rtxn, user_id)
+            .map_err(|e| eyre::eyre!("Failed to get credentials: {:?}", e))?;
+
+        Ok(credentials.unwrap_or_default())
+    }
+
+    pub fn vector_index_save(&self, user_id: u64, manifest: IndexManifest) -> Result<()> {
+        let mut wtxn = self.create_wtxn()?;
+        let user_id = &BEU64::new(user_id);
+        self.vector_index_db
+            .put(&mut wtxn, user_id, &manifest)
+            .map_err(|e| eyre::eyre!("Failed to save vector_index: {:?}", e))?;
+
+        wtxn.commit()
+            .map_err(|e| eyre::eyre!("Failed to commit vector_index: {:?}", e))
+    }
+
+    pub fn vector_index(&self, user_id: u64) -> Result<Option<IndexManifest>> {
+        let rtxn = self.create_rtxn()?;
+        let user_id = &BEU64::new(user_id);
+        let manifest = self
+            .vector_index_db
+            .get(&rtxn, user_id)
+            .map_err(|e| eyre::eyre!("Failed to get vector_index: {:?}", e))?;
+
+        Ok(manifest)
+    }
+
+    pub fn vector_index_user_ids(&self) -> Result<Vec<u64>> {
+        let rtxn = self.create_rtxn()?;
+        let user_ids = self
+            .vector_index_db
+            .remap_data_type::<DecodeIgnore>()
+            .iter(&rtxn)
+            .map_err(|e| eyre::eyre!("Failed to get vector_index: {:?}", e))?
+            .filter_map(|entry| entry.ok().map(|(user_id, _)| user_id.get()))
+            .collect();
+
+        Ok(user_ids)
+    }
+
+    /// Swaps a bundle for its new version in one transaction, so readers see either
+    /// the old set of sources or the new one, never a mix. The version follows the one
+    /// stored, concurrent uploads of a name each get their own.
+    pub fn bundle_replace(&self, bundle: Bundle, sources: Vec<Source>) -> Result<Bundle> {
+        let mut wtxn = self.create_wtxn()?;
+
+        let previous = self
+            .bundle_db
+            .get(&wtxn, bundle.key().as_str())
+            .map_err(|e| eyre::eyre!("Failed to get bundle: {:?}", e))?;
+        let bundle = Bundle {
+            version: previous
+                .as_ref()
+                .map(|bundle| bundle.version + 1)
+                .unwrap_or(1),
+            ..bundle
+        };
+        let stale_uris = previous.map(|bundle| bundle.uris).unwrap_or_default();
+
+        for source in sources.iter() {
+            self.source_cache_db
+                .put(&mut wtxn, source.uri.as_str().trim(), source)
+                .map_err(|e| eyre::eyre!("Failed to save source_cache: {:?}", e))?;
+        }
+
+        for uri in stale_uris.iter() {
+            self.source_cache_db
+                .delete(&mut wtxn, uri)
+                .map_err(|e| eyre::eyre!("Failed to delete source_cache: {:?}", e))?;
+        }
+
+        let user_id = &BEU64::new(bundle.user_id);
+        let mut user_links = self
+            .user_sources_db
+            .get(&wtxn, user_id)
+            .map_err(|e| eyre::eyre!("Failed to get user_sources: {:?}", e))?
+            .unwrap_or_default();
+        user_links.retain(|link| !stale_uris.contains(&link.uri));
+        user_links.extend(bundle.uris.iter().map(|uri| LinkedSource {
+            uri: uri.clone(),
+            tags: Default::default(),
+        }));
+        self.user_sources_db
+            .put(&mut wtxn, user_id, &user_links)
+            .map_err(|e| eyre::eyre!("Failed to save user_sources: {:?}", e))?;
+
+        self.bundle_db
+            .put(&mut wtxn, bundle.key().as_str(), &bundle)
+            .map_err(|e| eyre::eyre!("Failed to save bundle: {:?}", e))?;
+
+        wtxn.commit()
+            .map(|_| bundle)
+            .map_err(|e| eyre::eyre!("Failed to commit bundle: {:?}", e))
+    }
+
+    pub fn bundles(&self, bundle_id_prefix: &str) -> Result<Vec<Bundle>> {
+        let rtxn = self.create_rtxn()?;
+        let bundles = self
+            .bundle_db
+            .prefix_iter(&rtxn, bundle_id_prefix)
+            .map_err(|e| eyre::eyre!("Failed to get bundles: {:?}", e))?
+            .filter_map(|bundle| bundle.ok().map(|(_, bundle)| bundle))
+            .collect();
+
+        Ok(bundles)
+    }
 }
 
 lazy_static! {
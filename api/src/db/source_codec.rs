@@ -0,0 +1,348 @@
+use std::{borrow::Cow, env};
+
+use bincode::Options;
+use eyre::Result;
+use half::f16;
+use heed::{BytesDecode, BytesEncode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    embed_pool::Embedding,
+    types::source::{Chunks, FetchStatus, Source, Tags},
+};
+
+// json records always start with '{', binary ones with their format
+const BINARY_FORMAT: u8 = 1;
+
+/// How embeddings are written, records remember the one they were written with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmbeddingStorage {
+    F32,
+    F16,
+    // one scale per vector, values in -127..=127
+    Int8,
+}
+
+lazy_static! {
+    /// Set with `EMBEDDING_STORAGE` (f32, f16 or int8), f32 by default.
+    pub static ref EMBEDDING_STORAGE: EmbeddingStorage =
+        match env::var("EMBEDDING_STORAGE").as_deref() {
+            Ok("f16") => EmbeddingStorage::F16,
+            Ok("int8") => EmbeddingStorage::Int8,
+            _ => EmbeddingStorage::F32,
+        };
+}
+
+/// Stores sources as a small bincode header followed by the packed embeddings,
+/// still reading the json records written before.
+pub struct SourceCodec;
+
+#[derive(Serialize, Deserialize)]
+struct StoredSource<'a> {
+    uri: String,
+    expires: u32,
+    created_at: u32,
+    fetch_status: FetchStatus,
+    url: String,
+    model: String,
+    dimension: usize,
+    sentences: Vec<String>,
+    vector_len: u32,
+    #[serde(borrow)]
+    embeddings: StoredEmbeddings<'a>,
+}
+
+// the packed values are borrowed from the record when decoding
+#[derive(Serialize, Deserialize)]
+enum StoredEmbeddings<'a> {
+    F32(#[serde(borrow)] Cow<'a, [u8]>),
+    F16(#[serde(borrow)] Cow<'a, [u8]>),
+    Int8 {
+        scales: Vec<f32>,
+        #[serde(borrow)]
+        values: Cow<'a, [u8]>,
+    },
+}
+
+// what bincode::serialize writes with
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+pub fn is_json(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&b'{')
+}
+
+pub fn encode(source: &Source, storage: EmbeddingStorage) -> Result<Vec<u8>> {
+    let (sentences, embeddings) = &source.chunks.value;
+    let vector_len = embeddings.first().map(Vec::len).unwrap_or_default();
+    if embeddings
+        .iter()
+        .any(|embedding| embedding.len() != vector_len)
+    {
+        return Err(eyre::eyre!("Embeddings of {} differ in length", source.uri));
+    }
+
+    let values = embeddings.iter().flatten();
+    let embeddings = match storage {
+        EmbeddingStorage::F32 => {
+            StoredEmbeddings::F32(values.flat_map(|value| value.to_le_bytes()).collect())
+        }
+        EmbeddingStorage::F16 => StoredEmbeddings::F16(
+            values
+                .flat_map(|value| f16::from_f32(*value).to_le_bytes())
+                .collect(),
+        ),
+        EmbeddingStorage::Int8 => {
+            let scales = embeddings
+                .iter()
+                .map(|embedding| {
+                    let max = embedding
+                        .iter()
+                        .fold(0.0f32, |max, value| max.max(value.abs()));
+                    if max == 0.0 {
+                        1.0
+                    } else {
+                        max / 127.0
+                    }
+                })
+                .collect::<Vec<_>>();
+            let values = embeddings
+                .iter()
+                .zip(scales.iter())
+                .flat_map(|(embedding, scale)| {
+                    embedding
+                        .iter()
+                        .map(move |value| (value / scale).round() as i8 as u8)
+                })
+                .collect();
+            StoredEmbeddings::Int8 { scales, values }
+        }
+    };
+
+    let stored = StoredSource {
+        uri: source.uri.clone(),
+        expires: source.expires,
+        created_at: source.created_at,
+        fetch_status: source.fetch_status.clone(),
+        url: source.chunks.url.clone(),
+        model: source.chunks.model.clone(),
+        dimension: source.chunks.dimension,
+        sentences: sentences.clone(),
+        vector_len: vector_len as u32,
+        embeddings,
+    };
+
+    let mut bytes = vec![BINARY_FORMAT];
+    bincode::serialize_into(&mut bytes, &stored)?;
+    Ok(bytes)
+}
+
+/// Decodes a record, the vectors are read straight from the stored bytes
+/// into the embeddings the source owns.
+pub fn decode(bytes: &[u8]) -> Result<Source> {
+    match bytes.first() {
+        Some(b'{') => return Ok(serde_json::from_slice(bytes)?),
+        Some(&BINARY_FORMAT) => {}
+        _ => return Err(eyre::eyre!("Unknown source format")),
+    }
+
+    let mut deserializer = bincode::Deserializer::from_slice(&bytes[1..], bincode_options());
+    let stored = StoredSource::deserialize(&mut deserializer)?;
+
+    let vector_len = stored.vector_len as usize;
+    let embeddings: Vec<Embedding> = match stored.embeddings {
+        _ if vector_len == 0 => vec![vec![]; stored.sentences.len()],
+        StoredEmbeddings::F32(bytes) => bytes
+            .chunks(vector_len * 4)
+            .map(|embedding| {
+                embedding
+                    .chunks_exact(4)
+                    .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                    .collect()
+            })
+            .collect(),
+        StoredEmbeddings::F16(bytes) => bytes
+            .chunks(vector_len * 2)
+            .map(|embedding| {
+                embedding
+                    .chunks_exact(2)
+                    .map(|value| f16::from_le_bytes([value[0], value[1]]).to_f32())
+                    .collect()
+            })
+            .collect(),
+        StoredEmbeddings::Int8 { scales, values } => values
+            .chunks(vector_len)
+            .zip(scales)
+            .map(|(embedding, scale)| {
+                embedding
+                    .iter()
+                    .map(|value| *value as i8 as f32 * scale)
+                    .collect()
+            })
+            .collect(),
+    };
+    if embeddings.len() != stored.sentences.len()
+        || embeddings
+            .iter()
+            .any(|embedding| embedding.len() != vector_len)
+    {
+        return Err(eyre::eyre!("Corrupt embeddings for {}", stored.uri));
+    }
+
+    Ok(Source {
+        uri: stored.uri,
+        expires: stored.expires,
+        created_at: stored.created_at,
+        chunks: Chunks {
+            url: stored.url,
+            value: (stored.sentences, embeddings),
+            model: stored.model,
+            dimension: stored.dimension,
+        },
+        fetch_status: stored.fetch_status,
+        tags: Tags::default(),
+    })
+}
+
+// heed only wants to know whether it worked, the reason is logged here
+impl<'a> BytesEncode<'a> for SourceCodec {
+    type EItem = Source;
+
+    fn bytes_encode(source: &'a Source) -> Option<Cow<'a, [u8]>> {
+        encode(source, *EMBEDDING_STORAGE)
+            .map(Cow::Owned)
+            .map_err(|e| log::error!("Failed to encode source {}: {}", source.uri, e))
+            .ok()
+    }
+}
+
+impl<'a> BytesDecode<'a> for SourceCodec {
+    type DItem = Source;
+
+    fn bytes_decode(bytes: &'a [u8]) -> Option<Source> {
+        decode(bytes)
+            .map_err(|e| log::error!("Failed to decode source: {}", e))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(chunk_count: usize, dimension: usize) -> Source {
+        Source {
+            uri: "https://thepagebot.com/docs".to_string(),
+            expires: 100,
+            created_at: 1000,
+            chunks: Chunks {
+                url: "https://thepagebot.com/docs".to_string(),
+                value: (
+                    (0..chunk_count)
+                        .map(|i| format!("Sentence number {} of the docs.", i))
+                        .collect(),
+                    (0..chunk_count)
+                        .map(|i| {
+                            (0..dimension)
+                                .map(|j| ((i * dimension + j) as f32 * 0.37).sin())
+                                .collect()
+                        })
+                        .collect(),
+                ),
+                model: "fake/384".to_string(),
+                dimension,
+            },
+            fetch_status: FetchStatus::Failed("timeout".to_string()),
+            tags: Tags::from([("version".to_string(), "2".to_string())]),
+        }
+    }
+
+    fn max_error(a: &Source, b: &Source) -> f32 {
+        a.chunks
+            .value
+            .1
+            .iter()
+            .flatten()
+            .zip(b.chunks.value.1.iter().flatten())
+            .fold(0.0, |max, (a, b)| max.max((a - b).abs()))
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let source = source(3, 8);
+        let decoded =
+            decode(&encode(&source, EmbeddingStorage::F32).expect("encode")).expect("decode");
+        assert_eq!(decoded.chunks.value, source.chunks.value);
+        assert_eq!(decoded.fetch_status, source.fetch_status);
+        assert_eq!(decoded.chunks.model, source.chunks.model);
+        // tags belong to the accounts' links, never to the shared record
+        assert!(decoded.tags.is_empty());
+
+        let decoded =
+            decode(&encode(&source, EmbeddingStorage::F16).expect("encode")).expect("decode");
+        assert_eq!(decoded.chunks.value.0, source.chunks.value.0);
+        assert!(max_error(&source, &decoded) < 1e-3);
+
+        let decoded =
+            decode(&encode(&source, EmbeddingStorage::Int8).expect("encode")).expect("decode");
+        assert!(max_error(&source, &decoded) < 1e-2);
+    }
+
+    #[test]
+    fn legacy_json() {
+        let source = source(2, 4);
+        let json = serde_json::to_vec(&source).expect("json");
+        assert!(is_json(&json));
+
+        let decoded = decode(&json).expect("decode");
+        assert_eq!(decoded.chunks.value, source.chunks.value);
+    }
+
+    // cargo test --release storage_benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn storage_benchmark() {
+        // roughly a 500 page sitemap, 40 chunks a page
+        let sources = (0..500).map(|_| source(40, 384)).collect::<Vec<_>>();
+
+        let started = std::time::Instant::now();
+        let json = sources
+            .iter()
+            .map(|source| serde_json::to_vec(source).expect("json"))
+            .collect::<Vec<_>>();
+        println!("json encode {:?}", started.elapsed());
+        let started = std::time::Instant::now();
+        json.iter().for_each(|bytes| {
+            decode(bytes).expect("decode");
+        });
+        println!(
+            "json: {} bytes, decode {:?}",
+            json.iter().map(Vec::len).sum::<usize>(),
+            started.elapsed()
+        );
+
+        for storage in [
+            EmbeddingStorage::F32,
+            EmbeddingStorage::F16,
+            EmbeddingStorage::Int8,
+        ] {
+            let binary = sources
+                .iter()
+                .map(|source| encode(source, storage).expect("encode"))
+                .collect::<Vec<_>>();
+            let started = std::time::Instant::now();
+            binary.iter().for_each(|bytes| {
+                decode(bytes).expect("decode");
+            });
+            println!(
+                "{:?}: {} bytes, decode {:?}",
+                storage,
+                binary.iter().map(Vec::len).sum::<usize>(),
+                started.elapsed()
+            );
+        }
+    }
+}
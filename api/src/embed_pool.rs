@@ -1,20 +1,87 @@
-use std::env;
+use std::{
+    env,
+    fmt::{Display, Formatter},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::TrySendError;
 
 use eyre::Result;
-use rust_bert::pipelines::sentence_embeddings::{
-    SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType,
-};
+use serde::Serialize;
+
+use crate::embedder::{Embedder, EmbedderConfig};
 
 pub struct EmbeddingModel {
     queue_recv: crossbeam::channel::Receiver<EmbedTask>,
     queue_send: crossbeam::channel::Sender<EmbedTask>,
+    pub config: EmbedderConfig,
+    thread_count: usize,
+    // workers that loaded and warmed up their model
+    ready_workers: AtomicUsize,
+    // a batch closes once it holds max_batch_size sentences or max_batch_wait has passed
+    max_batch_size: usize,
+    max_batch_wait: Duration,
+    // how long a task may take, from submission to its embeddings, before the caller gives up
+    pub timeout: Duration,
+    pub query_timeout: Duration,
+    metrics: EmbedPoolMetrics,
+}
+
+/// Seconds clients are asked to wait before retrying when the pool is saturated.
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+#[derive(Debug)]
+pub enum EmbedPoolError {
+    // the queue stayed full until the task timed out
+    Saturated,
+    Timeout,
+    // the worker handling the task went away without answering
+    WorkerLost,
 }
 
 pub type Embedding = Vec<f32>;
-pub struct EmbedTask(
-    tokio::sync::oneshot::Sender<Result<Vec<Embedding>>>,
-    Vec<String>,
-);
+pub struct EmbedTask {
+    sender: tokio::sync::oneshot::Sender<Result<Vec<Embedding>>>,
+    sentences: Vec<String>,
+    queued_at: Instant,
+}
+
+#[derive(Default)]
+struct EmbedPoolMetrics {
+    batch_count: AtomicU64,
+    task_count: AtomicU64,
+    sentence_count: AtomicU64,
+    max_batch_size: AtomicU64,
+    queue_wait_ms: AtomicU64,
+    max_queue_wait_ms: AtomicU64,
+    worker_restarts: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbedPoolStats {
+    pub queue_len: usize,
+    pub batch_count: u64,
+    pub task_count: u64,
+    pub avg_batch_size: f64,
+    pub max_batch_size: u64,
+    pub avg_queue_wait_ms: f64,
+    pub max_queue_wait_ms: u64,
+    pub worker_restarts: u64,
+}
+
+impl Display for EmbedPoolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmbedPoolError::Saturated => write!(f, "Embedding queue is saturated"),
+            EmbedPoolError::Timeout => write!(f, "Embedding timed out"),
+            EmbedPoolError::WorkerLost => write!(f, "Embedding worker stopped"),
+        }
+    }
+}
+
+impl std::error::Error for EmbedPoolError {}
 
 use std::sync::Once;
 
@@ -22,43 +89,47 @@ static INIT: Once = Once::new();
 
 impl EmbeddingModel {
     fn new() -> Self {
-        let (queue_send, queue_recv) = crossbeam::channel::bounded::<EmbedTask>(5000);
+        let var = |name: &str| env::var(name).ok().and_then(|value| value.parse().ok());
+        let (queue_send, queue_recv) =
+            crossbeam::channel::bounded::<EmbedTask>(var("EMBED_QUEUE_SIZE").unwrap_or(5000));
+
         Self {
             queue_recv,
             queue_send,
+            config: EmbedderConfig::from_env(),
+            thread_count: var("EMBED_THREADS").unwrap_or(4),
+            ready_workers: AtomicUsize::new(0),
+            max_batch_size: var("EMBED_BATCH_SIZE").unwrap_or(32),
+            max_batch_wait: Duration::from_millis(var("EMBED_BATCH_WAIT_MS").unwrap_or(5)),
+            timeout: Duration::from_millis(var("EMBED_TIMEOUT_MS").unwrap_or(60_000)),
+            query_timeout: Duration::from_millis(var("EMBED_QUERY_TIMEOUT_MS").unwrap_or(5_000)),
+            metrics: EmbedPoolMetrics::default(),
         }
     }
 
     pub fn run(&self) {
         INIT.call_once(|| {
-            #[cfg(not(debug_assertions))]
-            let thread_count = env::args()
-                .nth(1)
-                .and_then(|s| s.parse::<usize>().ok())
-                .unwrap_or(4);
-
-            #[cfg(debug_assertions)]
-            let thread_count = 1;
+            let thread_count = self.thread_count;
 
             #[cfg(debug_assertions)]
             println!("EmbedPool Started");
 
             log::info!("Model Thread Count {}", thread_count);
-            let task_worker = |_worker_index: usize| {
-                #[cfg(debug_assertions)]
-                println!("Worker {} initializing", _worker_index);
-
-                let model =
-                    SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL6V2)
-                        .create_model()
-                        .expect("Failed to create model");
-
-                #[cfg(debug_assertions)]
-                println!("Worker {} initialized", _worker_index);
-                loop {
-                    while let Ok(EmbedTask(sender, sentences)) = self.queue_recv.recv() {
-                        let result = model.encode(&sentences).map_err(|e| e.into());
-                        sender.send(result).unwrap();
+            log::info!("Embedder {:?}", self.config);
+            // a wrong model path, checksum or api key stops startup instead of restarting workers
+            if let Err(e) = self.load_model() {
+                log::error!("Failed to load embedder: {}", e);
+                std::process::exit(1);
+            }
+
+            // a panicking worker drops its batch, failing those callers, and is started again
+            let supervised_worker = |worker_index: usize| loop {
+                match catch_unwind(AssertUnwindSafe(|| self.work(worker_index))) {
+                    Ok(()) => break,
+                    Err(_) => {
+                        log::error!("Embed worker {} panicked, restarting", worker_index);
+                        self.metrics.worker_restarts.fetch_add(1, Ordering::Relaxed);
+                        std::thread::sleep(Duration::from_secs(1));
                     }
                 }
             };
@@ -70,20 +141,259 @@ impl EmbeddingModel {
 
             pool.scope(|s| {
                 for i in 0..thread_count {
-                    s.spawn(move |_| task_worker(i));
+                    s.spawn(move |_| supervised_worker(i));
                 }
             });
         });
     }
 
+    /// Runs batches until the queue is disconnected.
+    fn work(&self, worker_index: usize) {
+        #[cfg(debug_assertions)]
+        println!("Worker {} initializing", worker_index);
+
+        let model = match self.load_model() {
+            Ok(model) => model,
+            Err(e) => {
+                log::error!(
+                    "Embed worker {} failed to load embedder: {}",
+                    worker_index,
+                    e
+                );
+                std::process::exit(1);
+            }
+        };
+        let _ready = ReadyGuard::new(&self.ready_workers);
+
+        #[cfg(debug_assertions)]
+        println!("Worker {} initialized", worker_index);
+
+        while let Some(batch) = self.next_batch() {
+            let sentences = batch
+                .iter()
+                .flat_map(|task| task.sentences.iter().cloned())
+                .collect::<Vec<_>>();
+
+            match model.encode(&sentences) {
+                Ok(embeddings) => {
+                    let lengths = batch.iter().map(|task| task.sentences.len());
+                    match split_embeddings(embeddings, lengths) {
+                        Some(split) => {
+                            for (task, embeddings) in batch.into_iter().zip(split) {
+                                _ = task.sender.send(Ok(embeddings));
+                            }
+                        }
+                        None => {
+                            for task in batch {
+                                _ = task.sender.send(Err(eyre::eyre!(
+                                    "Embedder returned the wrong number of embeddings"
+                                )));
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    for task in batch {
+                        _ = task.sender.send(Err(eyre::eyre!("{}", e)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Creates the embedder and encodes once, the first encode is much slower than the rest and
+    /// shouldn't land on a request.
+    fn load_model(&self) -> Result<Box<dyn Embedder>> {
+        let model = self.config.create()?;
+        model.encode(&["warm up".to_string()])?;
+        Ok(model)
+    }
+
+    /// Blocks for the first task, then gathers whatever else arrives until the batch is full or the wait is over.
+    fn next_batch(&self) -> Option<Vec<EmbedTask>> {
+        let mut batch = vec![];
+        let mut sentence_count = 0;
+        while batch.is_empty() {
+            let first = self.queue_recv.recv().ok()?;
+            // callers that timed out aren't waiting for the embeddings anymore
+            if !first.sender.is_closed() {
+                sentence_count = first.sentences.len();
+                batch.push(first);
+            }
+        }
+
+        let deadline = Instant::now() + self.max_batch_wait;
+        while sentence_count < self.max_batch_size {
+            match self.queue_recv.recv_deadline(deadline) {
+                Ok(task) if task.sender.is_closed() => {}
+                Ok(task) => {
+                    sentence_count += task.sentences.len();
+                    batch.push(task);
+                }
+                Err(_) => break,
+            }
+        }
+
+        self.metrics.record(&batch, sentence_count);
+        Some(batch)
+    }
+
+    pub fn stats(&self) -> EmbedPoolStats {
+        let metrics = &self.metrics;
+        let batch_count = metrics.batch_count.load(Ordering::Relaxed);
+        let task_count = metrics.task_count.load(Ordering::Relaxed);
+        let average = |total: u64, count: u64| {
+            if count == 0 {
+                0.0
+            } else {
+                total as f64 / count as f64
+            }
+        };
+
+        EmbedPoolStats {
+            queue_len: self.queue_recv.len(),
+            batch_count,
+            task_count,
+            avg_batch_size: average(metrics.sentence_count.load(Ordering::Relaxed), batch_count),
+            max_batch_size: metrics.max_batch_size.load(Ordering::Relaxed),
+            avg_queue_wait_ms: average(metrics.queue_wait_ms.load(Ordering::Relaxed), task_count),
+            max_queue_wait_ms: metrics.max_queue_wait_ms.load(Ordering::Relaxed),
+            worker_restarts: metrics.worker_restarts.load(Ordering::Relaxed),
+        }
+    }
+
+    /// New work should be turned away while this holds.
+    pub fn is_saturated(&self) -> bool {
+        self.queue_send.is_full()
+    }
+
+    /// Every worker has loaded and warmed up its model.
+    pub fn is_ready(&self) -> bool {
+        self.ready_workers.load(Ordering::Relaxed) >= self.thread_count
+    }
+
+    /// Id of the model the pool currently embeds with.
+    pub fn model_id(&self) -> String {
+        self.config.id()
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.config.dimension()
+    }
+
     pub async fn encode(&self, sentences: Vec<String>) -> Result<Vec<Embedding>> {
+        self.encode_with_timeout(sentences, self.timeout).await
+    }
+
+    /// Queues the sentences without blocking the runtime, waiting for room while the queue is full.
+    pub async fn encode_with_timeout(
+        &self,
+        sentences: Vec<String>,
+        timeout: Duration,
+    ) -> Result<Vec<Embedding>> {
+        let deadline = tokio::time::Instant::now() + timeout;
         let (sender, receiver) = tokio::sync::oneshot::channel::<Result<Vec<Embedding>>>();
-        let task = EmbedTask(sender, sentences);
-        self.queue_send.send(task)?;
-        receiver.await?
+        let mut task = EmbedTask {
+            sender,
+            sentences,
+            queued_at: Instant::now(),
+        };
+
+        loop {
+            match self.queue_send.try_send(task) {
+                Ok(()) => break,
+                Err(TrySendError::Full(returned)) => {
+                    if tokio::time::Instant::now() >= deadline {
+                        return Err(EmbedPoolError::Saturated.into());
+                    }
+                    task = returned;
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(EmbedPoolError::WorkerLost.into()),
+            }
+        }
+
+        match tokio::time::timeout_at(deadline, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(EmbedPoolError::WorkerLost.into()),
+            Err(_) => Err(EmbedPoolError::Timeout.into()),
+        }
+    }
+}
+
+impl EmbedPoolMetrics {
+    fn record(&self, batch: &[EmbedTask], sentence_count: usize) {
+        self.batch_count.fetch_add(1, Ordering::Relaxed);
+        self.task_count
+            .fetch_add(batch.len() as u64, Ordering::Relaxed);
+        self.sentence_count
+            .fetch_add(sentence_count as u64, Ordering::Relaxed);
+        self.max_batch_size
+            .fetch_max(sentence_count as u64, Ordering::Relaxed);
+
+        for task in batch {
+            let wait_ms = task.queued_at.elapsed().as_millis() as u64;
+            self.queue_wait_ms.fetch_add(wait_ms, Ordering::Relaxed);
+            self.max_queue_wait_ms.fetch_max(wait_ms, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counts a worker as ready for as long as it runs, including when it unwinds from a panic.
+struct ReadyGuard<'a>(&'a AtomicUsize);
+
+impl<'a> ReadyGuard<'a> {
+    fn new(ready_workers: &'a AtomicUsize) -> Self {
+        ready_workers.fetch_add(1, Ordering::Relaxed);
+        Self(ready_workers)
+    }
+}
+
+impl Drop for ReadyGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Hands each task back its own slice of the batch's embeddings.
+fn split_embeddings(
+    embeddings: Vec<Embedding>,
+    lengths: impl Iterator<Item = usize>,
+) -> Option<Vec<Vec<Embedding>>> {
+    let mut embeddings = embeddings.into_iter();
+    let mut split = vec![];
+    for length in lengths {
+        let task_embeddings = embeddings.by_ref().take(length).collect::<Vec<_>>();
+        if task_embeddings.len() != length {
+            return None;
+        }
+        split.push(task_embeddings);
+    }
+
+    match embeddings.next() {
+        Some(_) => None,
+        None => Some(split),
     }
 }
 
 lazy_static! {
     pub static ref EMBED_POOL: EmbeddingModel = EmbeddingModel::new();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_batch_embeddings() {
+        let embeddings = vec![vec![1.0], vec![2.0], vec![3.0]];
+        let split = split_embeddings(embeddings.clone(), [1, 0, 2].into_iter()).expect("split");
+        assert_eq!(
+            split,
+            vec![vec![vec![1.0]], vec![], vec![vec![2.0], vec![3.0]]]
+        );
+
+        assert!(split_embeddings(embeddings.clone(), [1, 1].into_iter()).is_none());
+        assert!(split_embeddings(embeddings, [2, 2].into_iter()).is_none());
+    }
+}
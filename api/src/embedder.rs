@@ -0,0 +1,367 @@
+use std::{
+    env, fs,
+    hash::{BuildHasher, Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use rust_bert::pipelines::sentence_embeddings::{
+    SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::embed_pool::Embedding;
+
+/// Turns sentences into embeddings, each pool worker owns one.
+pub trait Embedder {
+    /// Identifies the model the embeddings come from.
+    fn id(&self) -> String;
+    fn dimension(&self) -> usize;
+    fn encode(&self, sentences: &[String]) -> Result<Vec<Embedding>>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmbedderConfig {
+    RustBert {
+        model: String,
+        // load the weights from this directory instead of downloading them
+        path: Option<PathBuf>,
+    },
+    // any endpoint compatible with openai's POST /embeddings
+    OpenAi {
+        base_url: String,
+        api_key: String,
+        model: String,
+        dimension: usize,
+    },
+    Fake {
+        dimension: usize,
+    },
+}
+
+impl EmbedderConfig {
+    /// Reads `EMBEDDER` (rust_bert, openai or fake) and the matching `EMBEDDING_*` variables.
+    /// With `EMBEDDING_MODEL_PATH`, `EMBEDDING_MODEL` still names the model the directory holds.
+    pub fn from_env() -> Self {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+        #[cfg(not(test))]
+        let default_embedder = "rust_bert";
+        #[cfg(test)]
+        let default_embedder = "fake";
+
+        match var("EMBEDDER").as_deref().unwrap_or(default_embedder) {
+            "openai" => EmbedderConfig::OpenAi {
+                base_url: var("EMBEDDING_API_URL")
+                    .unwrap_or_else(|| "https://api.openai.com/v1".to_string()),
+                api_key: var("EMBEDDING_API_KEY").unwrap_or_default(),
+                model: var("EMBEDDING_MODEL")
+                    .unwrap_or_else(|| "text-embedding-3-small".to_string()),
+                dimension: var("EMBEDDING_DIMENSION")
+                    .and_then(|dimension| dimension.parse().ok())
+                    .unwrap_or(1536),
+            },
+            "fake" => EmbedderConfig::Fake {
+                dimension: var("EMBEDDING_DIMENSION")
+                    .and_then(|dimension| dimension.parse().ok())
+                    .unwrap_or(384),
+            },
+            _ => EmbedderConfig::RustBert {
+                model: var("EMBEDDING_MODEL").unwrap_or_else(|| "all-mini-lm-l6-v2".to_string()),
+                path: var("EMBEDDING_MODEL_PATH").map(PathBuf::from),
+            },
+        }
+    }
+
+    /// Same as the created embedder's id, without loading the model.
+    pub fn id(&self) -> String {
+        match self {
+            EmbedderConfig::RustBert { model, .. } => format!("rust-bert/{}", model),
+            EmbedderConfig::OpenAi { model, .. } => format!("openai/{}", model),
+            EmbedderConfig::Fake { dimension } => format!("fake/{}", dimension),
+        }
+    }
+
+    pub fn dimension(&self) -> usize {
+        match self {
+            EmbedderConfig::RustBert { model, .. } => rust_bert_model(model)
+                .map(|(_, dimension)| dimension)
+                .unwrap_or_default(),
+            EmbedderConfig::OpenAi { dimension, .. } => *dimension,
+            EmbedderConfig::Fake { dimension } => *dimension,
+        }
+    }
+
+    pub fn create(&self) -> Result<Box<dyn Embedder>> {
+        match self {
+            EmbedderConfig::RustBert { model, path } => {
+                Ok(Box::new(RustBertEmbedder::new(model, path.as_deref())?))
+            }
+            EmbedderConfig::OpenAi {
+                base_url,
+                api_key,
+                model,
+                dimension,
+            } => Ok(Box::new(OpenAiEmbedder {
+                client: reqwest::blocking::Client::builder()
+                    .timeout(std::time::Duration::from_secs(30))
+                    .build()?,
+                url: format!("{}/embeddings", base_url.trim_end_matches('/')),
+                api_key: api_key.clone(),
+                model: model.clone(),
+                dimension: *dimension,
+            })),
+            EmbedderConfig::Fake { dimension } => Ok(Box::new(FakeEmbedder {
+                dimension: *dimension,
+            })),
+        }
+    }
+}
+
+pub struct RustBertEmbedder {
+    name: String,
+    dimension: usize,
+    model: SentenceEmbeddingsModel,
+}
+
+fn rust_bert_model(name: &str) -> Result<(SentenceEmbeddingsModelType, usize)> {
+    match name {
+        "all-mini-lm-l6-v2" => Ok((SentenceEmbeddingsModelType::AllMiniLmL6V2, 384)),
+        "all-mini-lm-l12-v2" => Ok((SentenceEmbeddingsModelType::AllMiniLmL12V2, 384)),
+        "all-distilroberta-v1" => Ok((SentenceEmbeddingsModelType::AllDistilrobertaV1, 768)),
+        "distiluse-base-multilingual-cased" => Ok((
+            SentenceEmbeddingsModelType::DistiluseBaseMultilingualCased,
+            512,
+        )),
+        "paraphrase-albert-small-v2" => {
+            Ok((SentenceEmbeddingsModelType::ParaphraseAlbertSmallV2, 768))
+        }
+        _ => Err(eyre::eyre!("Unknown rust-bert model: {}", name)),
+    }
+}
+
+impl RustBertEmbedder {
+    fn new(name: &str, path: Option<&Path>) -> Result<Self> {
+        let (model_type, dimension) = rust_bert_model(name)?;
+
+        let model = match path {
+            Some(path) => {
+                verify_checksums(path)?;
+                SentenceEmbeddingsBuilder::local(path).create_model()?
+            }
+            None => SentenceEmbeddingsBuilder::remote(model_type).create_model()?,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            dimension,
+            model,
+        })
+    }
+}
+
+impl Embedder for RustBertEmbedder {
+    fn id(&self) -> String {
+        format!("rust-bert/{}", self.name)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn encode(&self, sentences: &[String]) -> Result<Vec<Embedding>> {
+        self.model.encode(sentences).map_err(|e| e.into())
+    }
+}
+
+/// Checks every file listed in the directory's `SHA256SUMS` (`sha256sum` output),
+/// refusing weight files (`.ot`) that aren't listed.
+fn verify_checksums(dir: &Path) -> Result<()> {
+    let manifest = fs::read_to_string(dir.join("SHA256SUMS"))
+        .map_err(|e| eyre::eyre!("Failed to read {}/SHA256SUMS: {}", dir.display(), e))?;
+
+    let mut listed = vec![];
+    for line in manifest.lines().filter(|line| !line.trim().is_empty()) {
+        let (expected, file) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| eyre::eyre!("Invalid SHA256SUMS line: {}", line))?;
+        // sha256sum marks binary mode with a leading '*'
+        let file = file.trim_start().trim_start_matches('*');
+
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(dir.join(file))?, &mut hasher)?;
+        let actual = format!("{:x}", hasher.finalize());
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(eyre::eyre!("Checksum mismatch for {}", file));
+        }
+        listed.push(dir.join(file));
+    }
+
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if path
+                .extension()
+                .map_or(false, |extension| extension == "ot")
+                && !listed.contains(&path)
+            {
+                return Err(eyre::eyre!("{} is not in SHA256SUMS", path.display()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub struct OpenAiEmbedder {
+    client: reqwest::blocking::Client,
+    url: String,
+    api_key: String,
+    model: String,
+    dimension: usize,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Embedding,
+}
+
+impl Embedder for OpenAiEmbedder {
+    fn id(&self) -> String {
+        format!("openai/{}", self.model)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn encode(&self, sentences: &[String]) -> Result<Vec<Embedding>> {
+        let mut request = self.client.post(&self.url).json(&json!({
+            "model": self.model,
+            "input": sentences,
+        }));
+        if !self.api_key.is_empty() {
+            request = request.bearer_auth(&self.api_key);
+        }
+
+        let mut response: EmbeddingsResponse = request.send()?.error_for_status()?.json()?;
+        if response.data.len() != sentences.len() {
+            return Err(eyre::eyre!(
+                "Expected {} embeddings, received {}",
+                sentences.len(),
+                response.data.len()
+            ));
+        }
+
+        response.data.sort_by_key(|data| data.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|data| data.embedding)
+            .collect())
+    }
+}
+
+/// Hashes words into buckets, so sentences sharing words end up close without any model.
+pub struct FakeEmbedder {
+    dimension: usize,
+}
+
+impl Embedder for FakeEmbedder {
+    fn id(&self) -> String {
+        format!("fake/{}", self.dimension)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn encode(&self, sentences: &[String]) -> Result<Vec<Embedding>> {
+        let embeddings = sentences
+            .iter()
+            .map(|sentence| {
+                let mut embedding = vec![0.0; self.dimension];
+                for word in sentence.split(|c: char| !c.is_alphanumeric()) {
+                    if word.is_empty() {
+                        continue;
+                    }
+                    // seeded so the buckets are the same across runs
+                    let mut hasher = ahash::RandomState::with_seeds(1, 2, 3, 4).build_hasher();
+                    word.to_lowercase().hash(&mut hasher);
+                    embedding[hasher.finish() as usize % self.dimension] += 1.0;
+                }
+
+                let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if norm > 0.0 {
+                    embedding.iter_mut().for_each(|x| *x /= norm);
+                }
+                embedding
+            })
+            .collect();
+
+        Ok(embeddings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::message::cosine_similarity;
+
+    #[test]
+    fn fake_embedder_is_deterministic() {
+        let embedder = EmbedderConfig::Fake { dimension: 64 }
+            .create()
+            .expect("embedder");
+        let sentences = vec![
+            "What is the refund policy".to_string(),
+            "refund policy".to_string(),
+            "Pricing starts at ten dollars".to_string(),
+        ];
+
+        let embeddings = embedder.encode(&sentences).expect("embeddings");
+        assert_eq!(embeddings, embedder.encode(&sentences).expect("embeddings"));
+        assert_eq!(embeddings[0].len(), 64);
+        assert!(
+            cosine_similarity(&embeddings[0], &embeddings[1])
+                > cosine_similarity(&embeddings[0], &embeddings[2])
+        );
+    }
+
+    #[test]
+    fn model_checksums() {
+        let dir = env::temp_dir().join(nanoid::nanoid!());
+        fs::create_dir_all(dir.join("0_Transformer")).expect("dir");
+        fs::write(dir.join("0_Transformer/rust_model.ot"), "weights").expect("weights");
+        fs::write(
+            dir.join("SHA256SUMS"),
+            format!("{} *0_Transformer/rust_model.ot\n", "0".repeat(64)),
+        )
+        .expect("manifest");
+        assert!(verify_checksums(&dir).is_err());
+
+        let digest = format!("{:x}", Sha256::digest(b"weights"));
+        fs::write(
+            dir.join("SHA256SUMS"),
+            format!("{}  0_Transformer/rust_model.ot\n", digest),
+        )
+        .expect("manifest");
+        assert!(verify_checksums(&dir).is_ok());
+
+        fs::write(dir.join("extra.ot"), "unlisted").expect("weights");
+        assert!(verify_checksums(&dir).is_err());
+
+        _ = fs::remove_dir_all(dir);
+    }
+}
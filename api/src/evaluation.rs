@@ -0,0 +1,388 @@
+use std::{fs, sync::Arc};
+
+use async_trait::async_trait;
+use eyre::Result;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use url_serde::SerdeUrl;
+
+use crate::{
+    chat_provider::Chat,
+    llm_retrieval::{get_response_stream, Operation},
+    notification::Notification,
+    types::{
+        history_item::HistoryItem,
+        message::{cosine_similarity, EvaluatedMessage, Message},
+        source::{Chunks, Tags},
+        user::User,
+    },
+};
+
+// cut-offs recall is reported at
+const RECALL_AT: [usize; 3] = [1, 5, 10];
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpectedOperation {
+    Answer,
+    NotFound,
+    Email,
+}
+
+/// One question of a golden dataset.
+#[derive(Debug, Deserialize)]
+pub struct GoldenItem {
+    pub question: String,
+    #[serde(default)]
+    pub history: Vec<HistoryItem>,
+    #[serde(default = "default_page_url")]
+    pub page_url: SerdeUrl,
+    #[serde(default)]
+    pub tags: Tags,
+    // uri the answer should be retrieved from, none for questions that can't be answered
+    pub expected_source: Option<String>,
+    pub expected_operation: ExpectedOperation,
+    pub reference_answer: Option<String>,
+}
+
+fn default_page_url() -> SerdeUrl {
+    serde_json::from_value(serde_json::Value::from("https://thepagebot.com"))
+        .expect("Default page url is valid")
+}
+
+/// Produces the bot's operation for an evaluated message, so runs can compare prompts or
+/// providers against the same retrieval.
+#[async_trait]
+pub trait Generator: Send + Sync {
+    async fn generate(
+        &self,
+        chat: &Chat,
+        message: &EvaluatedMessage,
+        history: Vec<HistoryItem>,
+    ) -> Result<Operation>;
+}
+
+/// What `/message` streams, with the answer chunks joined.
+pub struct StreamGenerator;
+
+#[async_trait]
+impl Generator for StreamGenerator {
+    async fn generate(
+        &self,
+        chat: &Chat,
+        message: &EvaluatedMessage,
+        history: Vec<HistoryItem>,
+    ) -> Result<Operation> {
+        let mut stream = Box::into_pin(get_response_stream(chat, message, history).await?);
+        let mut answer = String::new();
+        while let Some(operation) = stream.next().await {
+            match operation? {
+                Operation::Answer((message, _)) | Operation::Ask((message, _)) => {
+                    answer.push_str(&message)
+                }
+                operation if answer.is_empty() => return Ok(operation),
+                _ => break,
+            }
+        }
+        Ok(Operation::Answer((answer, Default::default())))
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct ItemResult {
+    pub question: String,
+    pub expected_source: Option<String>,
+    // position of the expected source's best chunk in the final ranking
+    pub source_rank: Option<usize>,
+    pub source_in_context: bool,
+    pub expected_operation: Option<ExpectedOperation>,
+    // none when the question went unanswered, in retrieval-only runs when it wasn't short-circuited
+    pub operation: Option<ExpectedOperation>,
+    pub answer: Option<String>,
+    pub answer_similarity: Option<f32>,
+    // the item failed to evaluate and counts as a miss
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct Report {
+    pub item_count: usize,
+    // share of questions with an expected source found within the first k chunks
+    pub recall_at: Vec<(usize, f32)>,
+    pub mrr: f32,
+    pub context_recall: f32,
+    // of the questions answered as not found, how many should have been
+    pub not_found_precision: Option<f32>,
+    pub not_found_recall: Option<f32>,
+    pub operation_accuracy: Option<f32>,
+    // cosine similarity of answers to their reference answers
+    pub answer_similarity: Option<f32>,
+    pub items: Vec<ItemResult>,
+}
+
+fn mean(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f32)
+}
+
+pub fn report(items: Vec<ItemResult>) -> Report {
+    let with_source = items
+        .iter()
+        .filter(|item| item.expected_source.is_some())
+        .collect::<Vec<_>>();
+    let share = |hit: &dyn Fn(&ItemResult) -> bool| {
+        mean(with_source.iter().map(|item| hit(item) as u8 as f32)).unwrap_or_default()
+    };
+
+    let recall_at = RECALL_AT
+        .iter()
+        .map(|k| {
+            (
+                *k,
+                share(&|item| item.source_rank.map_or(false, |rank| rank < *k)),
+            )
+        })
+        .collect();
+    let mrr = mean(with_source.iter().map(|item| {
+        item.source_rank
+            .map_or(0.0, |rank| 1.0 / (rank as f32 + 1.0))
+    }))
+    .unwrap_or_default();
+    let context_recall = share(&|item| item.source_in_context);
+
+    let is_not_found =
+        |operation: Option<ExpectedOperation>| operation == Some(ExpectedOperation::NotFound);
+    let not_found_precision = mean(
+        items
+            .iter()
+            .filter(|item| is_not_found(item.operation))
+            .map(|item| is_not_found(item.expected_operation) as u8 as f32),
+    );
+    let not_found_recall = mean(
+        items
+            .iter()
+            .filter(|item| is_not_found(item.expected_operation))
+            .map(|item| is_not_found(item.operation) as u8 as f32),
+    );
+    // failed items count against it, unanswered ones of retrieval-only runs don't
+    let operation_accuracy = mean(
+        items
+            .iter()
+            .filter(|item| item.operation.is_some() || item.error.is_some())
+            .filter_map(|item| {
+                let expected = item.expected_operation?;
+                Some((item.operation == Some(expected)) as u8 as f32)
+            }),
+    );
+
+    Report {
+        item_count: items.len(),
+        recall_at,
+        mrr,
+        context_recall,
+        not_found_precision,
+        not_found_recall,
+        operation_accuracy,
+        answer_similarity: mean(items.iter().filter_map(|item| item.answer_similarity)),
+        items,
+    }
+}
+
+fn operation_kind(operation: &Operation) -> ExpectedOperation {
+    match operation {
+        Operation::Answer(_) | Operation::Ask(_) => ExpectedOperation::Answer,
+        Operation::NotFound(_) => ExpectedOperation::NotFound,
+        Operation::Email(_) => ExpectedOperation::Email,
+    }
+}
+
+/// Evaluates the question against the account's cached sources, nothing is fetched, linked or sent.
+async fn evaluate_item(
+    user: &User,
+    chat: &Chat,
+    item: GoldenItem,
+    generator: Option<&dyn Generator>,
+) -> Result<ItemResult> {
+    let message = Message {
+        user_id: user.id,
+        sources: vec![],
+        query: item.question.clone(),
+        page_url: item.page_url,
+        tags: item.tags,
+        explain: true,
+        read_only: true,
+    };
+    let notification = Arc::new(Notification::silent(user.email.clone()));
+    let evaluated_message = message.evaluate(user, &item.history, notification).await?;
+
+    let mut ranked = evaluated_message
+        .candidates
+        .iter()
+        .flatten()
+        .filter(|candidate| candidate.rank.is_some())
+        .collect::<Vec<_>>();
+    ranked.sort_by_key(|candidate| candidate.rank);
+    let is_expected = |uri: &str| item.expected_source.as_deref() == Some(uri);
+    let source_rank = ranked
+        .iter()
+        .position(|candidate| is_expected(&candidate.source_uri));
+    let source_in_context = evaluated_message
+        .candidates
+        .iter()
+        .flatten()
+        .any(|candidate| candidate.in_context && is_expected(&candidate.source_uri));
+
+    let operation = match (&evaluated_message, generator) {
+        (
+            EvaluatedMessage {
+                not_found: true, ..
+            },
+            _,
+        ) => Some(Operation::NotFound(Default::default())),
+        (
+            EvaluatedMessage {
+                pinned_answer: Some(answer),
+                ..
+            },
+            _,
+        ) => Some(Operation::Answer((answer.clone(), Default::default()))),
+        (_, Some(generator)) => Some(
+            generator
+                .generate(chat, &evaluated_message, item.history.clone())
+                .await?,
+        ),
+        (_, None) => None,
+    };
+
+    let answer = match &operation {
+        Some(Operation::Answer((answer, _))) => Some(answer.clone()),
+        _ => None,
+    };
+    let answer_similarity = match (&answer, &item.reference_answer) {
+        (Some(answer), Some(reference_answer)) => Some(cosine_similarity(
+            &Chunks::query(answer.clone()).await?,
+            &Chunks::query(reference_answer.clone()).await?,
+        )),
+        _ => None,
+    };
+
+    Ok(ItemResult {
+        question: item.question,
+        expected_source: item.expected_source,
+        source_rank,
+        source_in_context,
+        expected_operation: Some(item.expected_operation),
+        operation: operation.as_ref().map(operation_kind),
+        answer,
+        answer_similarity,
+        error: None,
+    })
+}
+
+/// `pagebot evaluate <user_id> <dataset.json> [--retrieval-only]`: runs a golden dataset
+/// against the account's sources and prints the report as json, to diff between runs.
+pub async fn run(
+    user_id: u64,
+    dataset_path: &str,
+    generator: Option<&dyn Generator>,
+) -> Result<()> {
+    let user = User::by_id(user_id)?.ok_or_else(|| eyre::eyre!("User not found"))?;
+    let dataset: Vec<GoldenItem> = serde_json::from_slice(&fs::read(dataset_path)?)?;
+    let chat = Chat::for_user(&user)?;
+
+    let mut items = vec![];
+    for item in dataset {
+        let failed = ItemResult {
+            question: item.question.clone(),
+            expected_source: item.expected_source.clone(),
+            expected_operation: Some(item.expected_operation),
+            ..Default::default()
+        };
+        match evaluate_item(&user, &chat, item, generator).await {
+            Ok(result) => items.push(result),
+            Err(e) => {
+                log::error!("Failed to evaluate {:?}: {}", failed.question, e);
+                items.push(ItemResult {
+                    error: Some(e.to_string()),
+                    ..failed
+                });
+            }
+        }
+    }
+
+    println!("{}", serde_json::to_string_pretty(&report(items))?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(
+        source_rank: Option<usize>,
+        expected_operation: ExpectedOperation,
+        operation: ExpectedOperation,
+    ) -> ItemResult {
+        ItemResult {
+            expected_source: (expected_operation == ExpectedOperation::Answer)
+                .then(|| "https://thepagebot.com/pricing".to_string()),
+            source_rank,
+            source_in_context: source_rank.map_or(false, |rank| rank < 3),
+            expected_operation: Some(expected_operation),
+            operation: Some(operation),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn retrieval_and_not_found_metrics() {
+        use ExpectedOperation::*;
+
+        let report = report(vec![
+            item(Some(0), Answer, Answer),
+            item(Some(3), Answer, Answer),
+            item(None, Answer, NotFound),
+            item(None, NotFound, NotFound),
+            item(None, NotFound, Answer),
+        ]);
+
+        assert_eq!(report.item_count, 5);
+        assert_eq!(
+            report.recall_at,
+            vec![(1, 1.0 / 3.0), (5, 2.0 / 3.0), (10, 2.0 / 3.0)]
+        );
+        assert!((report.mrr - (1.0 + 0.25) / 3.0).abs() < 1e-6);
+        assert!((report.context_recall - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(report.not_found_precision, Some(0.5));
+        assert_eq!(report.not_found_recall, Some(0.5));
+        assert_eq!(report.operation_accuracy, Some(0.6));
+        assert_eq!(report.answer_similarity, None);
+    }
+
+    #[test]
+    fn retrieval_only_and_failed_items() {
+        use ExpectedOperation::*;
+
+        let unanswered = |expected_operation| ItemResult {
+            operation: None,
+            ..item(None, expected_operation, Answer)
+        };
+        let report = report(vec![
+            // short-circuited as not found, without asking the model
+            item(None, NotFound, NotFound),
+            item(None, Answer, NotFound),
+            unanswered(NotFound),
+            unanswered(Answer),
+            ItemResult {
+                error: Some("timeout".to_string()),
+                ..unanswered(Answer)
+            },
+        ]);
+
+        assert_eq!(report.not_found_precision, Some(0.5));
+        assert_eq!(report.not_found_recall, Some(0.5));
+        // the failed item is a miss, the unanswered ones aren't judged
+        assert!((report.operation_accuracy.expect("accuracy") - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(report.recall_at[0], (1, 0.0));
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::types::source::Source;
+
+// the usual BM25 parameters
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+// sources whose postings are kept around between messages
+const MAX_CACHED_SOURCES: usize = 10_000;
+
+/// Postings of one source's chunks, combined with the other sources' at query time.
+#[derive(Debug, Default)]
+pub struct SourceTerms {
+    chunk_lengths: Vec<u32>,
+    // term -> (chunk, term frequency)
+    postings: HashMap<String, Vec<(u32, u32)>>,
+}
+
+lazy_static! {
+    // uri -> (created_at, postings)
+    static ref SOURCE_TERMS: Mutex<HashMap<String, (u32, Arc<SourceTerms>)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Lowercased words, keeping codes like `sku-1234` or `v2.1` whole and adding their parts.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let is_joiner = |c: char| matches!(c, '-' | '_' | '.');
+
+    text.split(|c: char| !c.is_alphanumeric() && !is_joiner(c))
+        .map(|token| token.trim_matches(is_joiner))
+        .filter(|token| !token.is_empty())
+        .flat_map(|token| {
+            let token = token.to_lowercase();
+            let mut tokens = vec![];
+            if token.contains(is_joiner) {
+                tokens.extend(
+                    token
+                        .split(is_joiner)
+                        .filter(|part| !part.is_empty())
+                        .map(str::to_string),
+                );
+            }
+            tokens.push(token);
+            tokens
+        })
+        .collect()
+}
+
+impl SourceTerms {
+    pub fn new(chunks: &[String]) -> Self {
+        let mut terms = SourceTerms::default();
+        for (chunk, content) in chunks.iter().enumerate() {
+            let tokens = tokenize(content);
+            terms.chunk_lengths.push(tokens.len() as u32);
+
+            let mut frequencies = HashMap::<String, u32>::new();
+            for token in tokens {
+                *frequencies.entry(token).or_default() += 1;
+            }
+            for (token, frequency) in frequencies {
+                terms
+                    .postings
+                    .entry(token)
+                    .or_default()
+                    .push((chunk as u32, frequency));
+            }
+        }
+        terms
+    }
+}
+
+fn source_terms(source: &Source) -> Arc<SourceTerms> {
+    let mut cache = SOURCE_TERMS.lock().unwrap();
+    if let Some((created_at, terms)) = cache.get(&source.uri) {
+        if *created_at == source.created_at {
+            return terms.clone();
+        }
+    }
+
+    if cache.len() >= MAX_CACHED_SOURCES {
+        cache.clear();
+    }
+    let terms = Arc::new(SourceTerms::new(&source.chunks.value.0));
+    cache.insert(source.uri.clone(), (source.created_at, terms.clone()));
+    terms
+}
+
+/// BM25 scores of the kn best matching chunks, counting chunks across the sources in order.
+pub fn top_matching(sources: &[Source], query: &str, kn: usize) -> Vec<(usize, f32)> {
+    let terms = sources.iter().map(source_terms).collect::<Vec<_>>();
+    bm25(&terms, query, kn)
+}
+
+fn bm25(terms: &[Arc<SourceTerms>], query: &str, kn: usize) -> Vec<(usize, f32)> {
+    let mut query_terms = tokenize(query);
+    query_terms.sort();
+    query_terms.dedup();
+
+    let chunk_count = terms
+        .iter()
+        .map(|terms| terms.chunk_lengths.len())
+        .sum::<usize>() as f32;
+    let total_length = terms
+        .iter()
+        .flat_map(|terms| terms.chunk_lengths.iter())
+        .map(|length| *length as f32)
+        .sum::<f32>();
+    if chunk_count == 0.0 || total_length == 0.0 {
+        return vec![];
+    }
+    let average_length = total_length / chunk_count;
+
+    let mut scores = HashMap::<usize, f32>::new();
+    for term in query_terms {
+        let document_frequency = terms
+            .iter()
+            .filter_map(|terms| terms.postings.get(&term))
+            .map(Vec::len)
+            .sum::<usize>() as f32;
+        if document_frequency == 0.0 {
+            continue;
+        }
+        let idf =
+            (1.0 + (chunk_count - document_frequency + 0.5) / (document_frequency + 0.5)).ln();
+
+        let mut offset = 0;
+        for terms in terms {
+            for (chunk, frequency) in terms.postings.get(&term).into_iter().flatten() {
+                let frequency = *frequency as f32;
+                let length = terms.chunk_lengths[*chunk as usize] as f32;
+                let score = idf * frequency * (K1 + 1.0)
+                    / (frequency + K1 * (1.0 - B + B * length / average_length));
+                *scores.entry(offset + *chunk as usize).or_default() += score;
+            }
+            offset += terms.chunk_lengths.len();
+        }
+    }
+
+    let mut scores = scores.into_iter().collect::<Vec<_>>();
+    scores.sort_by(|(a_index, a), (b_index, b)| b.total_cmp(a).then(a_index.cmp(b_index)));
+    scores.truncate(kn);
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_codes() {
+        assert_eq!(
+            tokenize("Error ERR-404 on plan Pro, v2.1."),
+            vec!["error", "err", "404", "err-404", "on", "plan", "pro", "v2", "1", "v2.1"]
+        );
+    }
+
+    #[test]
+    fn exact_tokens_rank_first() {
+        let docs = Arc::new(SourceTerms::new(&[
+            "Our plans include Starter and Pro.".to_string(),
+            "Order SKU-1234 ships in two days.".to_string(),
+        ]));
+        let blog = Arc::new(SourceTerms::new(&[
+            "Shipping usually takes a few days.".to_string()
+        ]));
+
+        let hits = bm25(&[blog, docs], "when does sku-1234 ship", 10);
+        assert_eq!(hits[0].0, 2);
+        assert!(hits[0].1 > 0.0);
+        assert!(bm25(&[], "sku-1234", 10).is_empty());
+    }
+}
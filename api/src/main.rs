@@ -12,18 +12,27 @@ extern crate dotenv;
 extern crate unicode_segmentation;
 
 mod auth;
+mod calibration;
+mod chat_provider;
+mod crypto;
 mod db;
 mod email_templates;
 mod embed_pool;
+mod embedder;
+mod evaluation;
 mod jwt;
 mod lemonsqueezy;
+mod lexical_index;
 mod llm_retrieval;
 mod notification;
-mod openai;
+mod renderer;
+mod reranker;
+mod retrieval;
 mod routes;
 mod stats;
 mod token_map;
 mod types;
+mod vector_index;
 use routes::build_router;
 
 use env_logger::Env;
@@ -35,6 +44,32 @@ use tower_http::cors::{Any, CorsLayer};
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
     setup_logs();
+
+    // graphs are read up front so no search waits on loading one
+    match tokio::task::spawn_blocking(vector_index::load_indexes).await? {
+        Ok(count) => log::info!("Loaded {} vector indexes", count),
+        Err(e) => log::error!("Failed to load vector indexes: {}", e),
+    }
+
+    // pagebot calibrate <user_id> <samples.json>
+    // pagebot evaluate <user_id> <dataset.json> [--retrieval-only]
+    let args = std::env::args().collect::<Vec<_>>();
+    match args.as_slice() {
+        [_, command, user_id, samples_path] if command == "calibrate" => {
+            std::thread::spawn(|| EMBED_POOL.run());
+            return calibration::run(user_id.parse()?, samples_path).await;
+        }
+        [_, command, user_id, dataset_path, options @ ..] if command == "evaluate" => {
+            std::thread::spawn(|| EMBED_POOL.run());
+            let generator = match options.iter().any(|option| option == "--retrieval-only") {
+                true => None,
+                false => Some(&evaluation::StreamGenerator as &dyn evaluation::Generator),
+            };
+            return evaluation::run(user_id.parse()?, dataset_path, generator).await;
+        }
+        _ => {}
+    }
+
     read_stats();
 
     let cors = CorsLayer::new()
@@ -56,6 +91,26 @@ async fn main() -> Result<()> {
             .unwrap();
     });
 
+    // embeddings from a previous model are migrated once the pool is up
+    tokio::spawn(async {
+        let migrated = tokio::task::spawn_blocking(|| db::DB.source_cache_migrate())
+            .await
+            .map_err(eyre::Report::from)
+            .and_then(|migrated| migrated);
+        match migrated {
+            Ok(count) => log::info!("Moved {} sources to binary storage", count),
+            Err(e) => log::error!("Failed to move sources to binary storage: {}", e),
+        }
+        match types::source::Source::reembed_stale().await {
+            Ok(count) => log::info!("Re-embedded {} sources", count),
+            Err(e) => log::error!("Failed to re-embed sources: {}", e),
+        }
+        match types::pinned_answer::PinnedAnswer::reembed_stale().await {
+            Ok(count) => log::info!("Re-embedded {} pinned answers", count),
+            Err(e) => log::error!("Failed to re-embed pinned answers: {}", e),
+        }
+    });
+
     EMBED_POOL.run();
 
     let _ = server_handler.await;
@@ -8,6 +8,8 @@ use serde_json::json;
 #[derive(Clone)]
 pub struct Notification {
     context: Context,
+    // nothing is sent, for explanations and evaluation runs
+    silent: bool,
 }
 
 #[derive(Clone)]
@@ -36,10 +38,22 @@ impl Notification {
     pub fn new(email: String) -> Self {
         Self {
             context: Context { email },
+            silent: false,
+        }
+    }
+
+    pub fn silent(email: String) -> Self {
+        Self {
+            context: Context { email },
+            silent: true,
         }
     }
 
     pub async fn send(&self, event_type: NotificationType) -> Result<()> {
+        if self.silent {
+            return Ok(());
+        }
+
         let client = reqwest::Client::new()
             .post("https://api.zeptomail.com/v1.1/email")
             .header("Authorization", dotenv!("ZEPTO_KEY"));
@@ -0,0 +1,90 @@
+use async_trait::async_trait;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+/// Renders javascript heavy pages server side when the plain html has too little content.
+#[async_trait]
+pub trait Renderer: Send + Sync {
+    /// Returns the rendered html, None if rendering is disabled.
+    async fn render(&self, url: &str) -> Result<Option<String>>;
+}
+
+/// The renderer an account picked, stored on the user.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RendererConfig {
+    #[default]
+    ScraperApi,
+    // GET {endpoint}?url={url} returning the rendered html
+    SelfHosted {
+        endpoint: String,
+    },
+    Disabled,
+}
+
+impl RendererConfig {
+    pub fn renderer(&self) -> Box<dyn Renderer> {
+        match self {
+            RendererConfig::ScraperApi => Box::new(ScraperApi),
+            RendererConfig::SelfHosted { endpoint } => Box::new(SelfHosted {
+                endpoint: endpoint.clone(),
+            }),
+            RendererConfig::Disabled => Box::new(Disabled),
+        }
+    }
+}
+
+fn create_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent("PGBT")
+        .timeout(std::time::Duration::from_secs(60))
+        .build()
+        .expect("Unable to build reqwest client")
+}
+
+pub struct ScraperApi;
+
+#[async_trait]
+impl Renderer for ScraperApi {
+    async fn render(&self, url: &str) -> Result<Option<String>> {
+        let resp = create_client()
+            .get("https://api.scraperapi.com")
+            .query(&[
+                ("api_key", dotenv!("SCRAPER_API_KEY")),
+                ("url", url),
+                ("render", "true"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(Some(resp.text().await?))
+    }
+}
+
+pub struct SelfHosted {
+    pub endpoint: String,
+}
+
+#[async_trait]
+impl Renderer for SelfHosted {
+    async fn render(&self, url: &str) -> Result<Option<String>> {
+        let resp = create_client()
+            .get(&self.endpoint)
+            .query(&[("url", url)])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(Some(resp.text().await?))
+    }
+}
+
+pub struct Disabled;
+
+#[async_trait]
+impl Renderer for Disabled {
+    async fn render(&self, _url: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
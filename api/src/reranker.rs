@@ -0,0 +1,172 @@
+use std::{env, path::PathBuf, sync::Mutex};
+
+use eyre::Result;
+use rust_bert::{
+    bert::{BertConfig, BertForSequenceClassification},
+    Config,
+};
+use rust_tokenizers::tokenizer::{BertTokenizer, Tokenizer, TruncationStrategy};
+use tch::{nn, no_grad, Device, Kind, Tensor};
+
+/// Scores how well each candidate chunk answers the query, higher is better.
+pub trait Reranker: Send + Sync {
+    fn rerank(&self, query: &str, candidates: &[&str]) -> Result<Vec<f32>>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RerankerConfig {
+    Disabled,
+    // a bert cross-encoder (e.g. ms-marco-MiniLM-L-6-v2) converted for rust-bert:
+    // config.json, vocab.txt and rust_model.ot
+    CrossEncoder { path: PathBuf },
+}
+
+lazy_static! {
+    /// None unless `RERANKER=cross_encoder`, the model is loaded once at first use.
+    pub static ref RERANKER: Option<Box<dyn Reranker>> = RerankerConfig::from_env()
+        .create()
+        .map_err(|e| log::error!("Failed to create reranker: {}", e))
+        .ok()
+        .flatten();
+
+    /// How many re-ranked chunks are kept, `RERANK_TOP_N`.
+    pub static ref RERANK_TOP_N: usize = env::var("RERANK_TOP_N")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(20);
+}
+
+impl RerankerConfig {
+    /// Reads `RERANKER` (cross_encoder or disabled) and `RERANKER_MODEL_PATH`.
+    pub fn from_env() -> Self {
+        match (
+            env::var("RERANKER").as_deref(),
+            env::var("RERANKER_MODEL_PATH"),
+        ) {
+            (Ok("cross_encoder"), Ok(path)) => RerankerConfig::CrossEncoder {
+                path: PathBuf::from(path),
+            },
+            (Ok("cross_encoder"), Err(_)) => {
+                log::error!("Failed to create reranker: RERANKER_MODEL_PATH is not set");
+                RerankerConfig::Disabled
+            }
+            _ => RerankerConfig::Disabled,
+        }
+    }
+
+    pub fn create(&self) -> Result<Option<Box<dyn Reranker>>> {
+        match self {
+            RerankerConfig::Disabled => Ok(None),
+            RerankerConfig::CrossEncoder { path } => {
+                Ok(Some(Box::new(CrossEncoder::new(path.clone())?)))
+            }
+        }
+    }
+}
+
+struct CrossEncoderModel {
+    tokenizer: BertTokenizer,
+    model: BertForSequenceClassification,
+    // owns the weights the model reads
+    var_store: nn::VarStore,
+}
+
+pub struct CrossEncoder {
+    // tch modules aren't Sync, calls take turns
+    model: Mutex<CrossEncoderModel>,
+}
+
+impl CrossEncoder {
+    const MAX_LENGTH: usize = 256;
+
+    fn new(path: PathBuf) -> Result<Self> {
+        let config = BertConfig::from_file(path.join("config.json"));
+        let tokenizer = BertTokenizer::from_file(path.join("vocab.txt"), true, true)?;
+
+        let mut var_store = nn::VarStore::new(Device::cuda_if_available());
+        let model = BertForSequenceClassification::new(var_store.root(), &config)?;
+        var_store.load(path.join("rust_model.ot"))?;
+
+        Ok(Self {
+            model: Mutex::new(CrossEncoderModel {
+                tokenizer,
+                model,
+                var_store,
+            }),
+        })
+    }
+}
+
+impl Reranker for CrossEncoder {
+    fn rerank(&self, query: &str, candidates: &[&str]) -> Result<Vec<f32>> {
+        if candidates.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let model = self
+            .model
+            .lock()
+            .map_err(|_| eyre::eyre!("Reranker is poisoned"))?;
+        let pairs = candidates
+            .iter()
+            .map(|candidate| (query, *candidate))
+            .collect::<Vec<_>>();
+        let inputs = model.tokenizer.encode_pair_list(
+            &pairs,
+            Self::MAX_LENGTH,
+            &TruncationStrategy::OnlySecond,
+            0,
+        );
+
+        let length = inputs
+            .iter()
+            .map(|input| input.token_ids.len())
+            .max()
+            .unwrap_or_default();
+        let pad = |values: Vec<i64>| {
+            let mut values = values;
+            values.resize(length, 0);
+            values
+        };
+
+        let mut token_ids = vec![];
+        let mut segment_ids = vec![];
+        let mut mask = vec![];
+        for input in inputs {
+            mask.extend(pad(vec![1; input.token_ids.len()]));
+            token_ids.extend(pad(input.token_ids));
+            segment_ids.extend(pad(input.segment_ids.into_iter().map(i64::from).collect()));
+        }
+
+        let device = model.var_store.device();
+        let shape = [candidates.len() as i64, length as i64];
+        let token_ids = Tensor::of_slice(&token_ids).view(shape).to(device);
+        let segment_ids = Tensor::of_slice(&segment_ids).view(shape).to(device);
+        let mask = Tensor::of_slice(&mask).view(shape).to(device);
+
+        let logits = no_grad(|| {
+            model
+                .model
+                .forward_t(
+                    Some(&token_ids),
+                    Some(&mask),
+                    Some(&segment_ids),
+                    None,
+                    None,
+                    false,
+                )
+                .logits
+        });
+
+        // single logit models score directly, otherwise the last label is "relevant"
+        let label_count = logits.size()[1];
+        let scores = if label_count == 1 {
+            logits.squeeze_dim(1)
+        } else {
+            logits.softmax(-1, Kind::Float).select(1, label_count - 1)
+        };
+        Ok(Vec::<f32>::try_from(
+            scores.to_kind(Kind::Float).to(Device::Cpu),
+        )?)
+    }
+}
@@ -0,0 +1,663 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ops::RangeInclusive,
+};
+
+use serde::Serialize;
+
+use crate::{
+    embed_pool::Embedding,
+    reranker::Reranker,
+    types::message::{cosine_similarity, count_tokens},
+};
+
+/// Why a chunk made it into the context, sent along with the perf numbers for debugging.
+#[derive(Debug, Clone, Serialize, Default, PartialEq)]
+pub struct ChunkScore {
+    pub index: usize,
+    // cosine similarity to the query
+    pub vector_score: Option<f32>,
+    // BM25 score of the query terms
+    pub lexical_score: Option<f32>,
+    pub fused_score: f32,
+    // cross-encoder relevance, when re-ranking is on
+    pub rerank_score: Option<f32>,
+    // from the visitor's page or a source its page rule favours
+    pub boosted: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FusionWeights {
+    pub vector: f32,
+    pub lexical: f32,
+    // dampens the head of each ranking, 60 in the original paper
+    pub k: f32,
+    // multiplies the fused score of boosted chunks
+    pub page_boost: f32,
+}
+
+/// A fused candidate and what became of it, returned by the explain endpoint.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CandidateChunk {
+    pub source_uri: String,
+    pub content: String,
+    // position after fusion and boosting
+    pub fused_rank: usize,
+    // position after re-ranking and diversification, None if it was dropped
+    pub rank: Option<usize>,
+    pub in_context: bool,
+    #[serde(flatten)]
+    pub score: ChunkScore,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Diversity {
+    // trades relevance (1.0) against novelty (0.0) in maximal marginal relevance
+    pub lambda: f32,
+    pub max_per_source: Option<usize>,
+}
+
+/// How varied the retrieved chunks are, reported in `Perf`.
+#[derive(Debug, Clone, Serialize, Default, PartialEq)]
+pub struct DiversityStats {
+    // distinct sources among the fused candidates
+    pub candidate_sources: usize,
+    // after the diversification step
+    pub selected_sources: usize,
+    // in the assembled context
+    pub context_sources: usize,
+    pub context_chunks: usize,
+}
+
+lazy_static! {
+    /// Set with `RETRIEVAL_MMR_LAMBDA` (1.0 turns it off) and `RETRIEVAL_MAX_PER_SOURCE`.
+    pub static ref DIVERSITY: Diversity = Diversity {
+        lambda: env::var("RETRIEVAL_MMR_LAMBDA")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0.7),
+        max_per_source: env::var("RETRIEVAL_MAX_PER_SOURCE")
+            .ok()
+            .and_then(|value| value.parse().ok()),
+    };
+
+    /// Set with `RETRIEVAL_VECTOR_WEIGHT`, `RETRIEVAL_LEXICAL_WEIGHT`, `RETRIEVAL_RRF_K`
+    /// and `RETRIEVAL_PAGE_BOOST`.
+    pub static ref FUSION_WEIGHTS: FusionWeights = {
+        let var = |name: &str, default: f32| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        FusionWeights {
+            vector: var("RETRIEVAL_VECTOR_WEIGHT", 1.0),
+            lexical: var("RETRIEVAL_LEXICAL_WEIGHT", 1.0),
+            k: var("RETRIEVAL_RRF_K", 60.0),
+            page_boost: var("RETRIEVAL_PAGE_BOOST", 1.5),
+        }
+    };
+}
+
+/// Reciprocal rank fusion of the vector and lexical rankings, best first.
+pub fn fuse(
+    vector_hits: &[(usize, f32)],
+    lexical_hits: &[(usize, f32)],
+    weights: &FusionWeights,
+    kn: usize,
+) -> Vec<ChunkScore> {
+    let mut scores = HashMap::<usize, ChunkScore>::new();
+
+    for (rank, (index, score)) in vector_hits.iter().enumerate() {
+        let chunk = scores.entry(*index).or_insert_with(|| ChunkScore {
+            index: *index,
+            ..Default::default()
+        });
+        chunk.vector_score = Some(*score);
+        chunk.fused_score += weights.vector / (weights.k + rank as f32 + 1.0);
+    }
+
+    for (rank, (index, score)) in lexical_hits.iter().enumerate() {
+        let chunk = scores.entry(*index).or_insert_with(|| ChunkScore {
+            index: *index,
+            ..Default::default()
+        });
+        chunk.lexical_score = Some(*score);
+        chunk.fused_score += weights.lexical / (weights.k + rank as f32 + 1.0);
+    }
+
+    let mut scores = scores.into_values().collect::<Vec<_>>();
+    scores.sort_by(|a, b| {
+        b.fused_score
+            .total_cmp(&a.fused_score)
+            .then(a.index.cmp(&b.index))
+    });
+    scores.truncate(kn);
+    scores
+}
+
+/// Favours the chunks `is_boosted` picks out, keeping the best `kn`.
+pub fn boost(
+    scores: Vec<ChunkScore>,
+    is_boosted: impl Fn(usize) -> bool,
+    weights: &FusionWeights,
+    kn: usize,
+) -> Vec<ChunkScore> {
+    let mut scores = scores
+        .into_iter()
+        .map(|score| match is_boosted(score.index) {
+            true => ChunkScore {
+                fused_score: score.fused_score * weights.page_boost,
+                boosted: true,
+                ..score
+            },
+            false => score,
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by(|a, b| {
+        b.fused_score
+            .total_cmp(&a.fused_score)
+            .then(a.index.cmp(&b.index))
+    });
+    scores.truncate(kn);
+    scores
+}
+
+/// Picks `kn` chunks by maximal marginal relevance, each one the most relevant while least
+/// similar to those already picked, skipping sources that reached `max_per_source`.
+/// `source_of` maps a chunk to its source.
+pub fn diversify(
+    scores: Vec<ChunkScore>,
+    embeddings: &[&Embedding],
+    source_of: impl Fn(usize) -> usize,
+    diversity: &Diversity,
+    kn: usize,
+) -> Vec<ChunkScore> {
+    let max_score = scores
+        .iter()
+        .map(|score| score.fused_score)
+        .fold(0.0f32, f32::max);
+    if max_score <= 0.0 || (diversity.lambda >= 1.0 && diversity.max_per_source.is_none()) {
+        return scores.into_iter().take(kn).collect();
+    }
+
+    let mut candidates = scores.into_iter().map(Some).collect::<Vec<_>>();
+    // highest similarity of each candidate to the selected chunks
+    let mut redundancy = vec![0.0f32; candidates.len()];
+    let mut per_source = HashMap::<usize, usize>::new();
+    let mut selected = vec![];
+
+    while selected.len() < kn {
+        let best = candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, candidate)| candidate.as_ref().map(|candidate| (i, candidate)))
+            .filter(|(_, candidate)| {
+                diversity.max_per_source.map_or(true, |max| {
+                    per_source.get(&source_of(candidate.index)).unwrap_or(&0) < &max
+                })
+            })
+            .map(|(i, candidate)| {
+                let relevance = candidate.fused_score / max_score;
+                let mmr = diversity.lambda * relevance - (1.0 - diversity.lambda) * redundancy[i];
+                (i, mmr)
+            })
+            .max_by(|(a_i, a), (b_i, b)| a.total_cmp(b).then(b_i.cmp(a_i)));
+        let best = match best {
+            Some((best, _)) => best,
+            None => break,
+        };
+
+        let chunk = candidates[best].take().unwrap();
+        for (i, candidate) in candidates.iter().enumerate() {
+            if let Some(candidate) = candidate {
+                let similarity =
+                    cosine_similarity(embeddings[candidate.index], embeddings[chunk.index]);
+                redundancy[i] = redundancy[i].max(similarity);
+            }
+        }
+        *per_source.entry(source_of(chunk.index)).or_default() += 1;
+        selected.push(chunk);
+    }
+
+    selected
+}
+
+/// Distinct sources the chunks come from.
+pub fn source_count(
+    chunks: impl Iterator<Item = usize>,
+    source_of: impl Fn(usize) -> usize,
+) -> usize {
+    chunks.map(source_of).collect::<HashSet<_>>().len()
+}
+
+/// Follows every candidate through selection and context assembly.
+pub fn explain(
+    candidates: &[ChunkScore],
+    selected: &[ChunkScore],
+    context_chunks: &[usize],
+    contents: &[String],
+    source_uri: impl Fn(usize) -> String,
+) -> Vec<CandidateChunk> {
+    let ranks = selected
+        .iter()
+        .enumerate()
+        .map(|(rank, score)| (score.index, rank))
+        .collect::<HashMap<_, _>>();
+    let context_chunks = context_chunks.iter().collect::<HashSet<_>>();
+
+    candidates
+        .iter()
+        .enumerate()
+        .map(|(fused_rank, score)| {
+            let rank = ranks.get(&score.index).copied();
+            CandidateChunk {
+                source_uri: source_uri(score.index),
+                content: contents[score.index].clone(),
+                fused_rank,
+                rank,
+                in_context: context_chunks.contains(&score.index),
+                // re-ranking adds its score to the selected copy
+                score: rank
+                    .map(|rank| selected[rank].clone())
+                    .unwrap_or_else(|| score.clone()),
+            }
+        })
+        .collect()
+}
+
+/// Re-scores the candidates against the query, keeping the best `top_n`.
+/// The fused order is kept if the re-ranker fails.
+pub fn rerank(
+    reranker: &dyn Reranker,
+    query: &str,
+    contents: &[String],
+    mut scores: Vec<ChunkScore>,
+    top_n: usize,
+) -> Vec<ChunkScore> {
+    let candidates = scores
+        .iter()
+        .map(|score| contents[score.index].as_str())
+        .collect::<Vec<_>>();
+
+    let rerank_scores = match reranker.rerank(query, &candidates) {
+        Ok(rerank_scores) if rerank_scores.len() == scores.len() => rerank_scores,
+        Ok(_) => {
+            log::error!("Reranker returned the wrong number of scores");
+            scores.truncate(top_n);
+            return scores;
+        }
+        Err(e) => {
+            log::error!("Failed to rerank: {}", e);
+            scores.truncate(top_n);
+            return scores;
+        }
+    };
+
+    let mut scores = scores
+        .into_iter()
+        .zip(rerank_scores)
+        .map(|(score, rerank_score)| ChunkScore {
+            rerank_score: Some(rerank_score),
+            ..score
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by(|a, b| b.rerank_score.unwrap().total_cmp(&a.rerank_score.unwrap()));
+    scores.truncate(top_n);
+    scores
+}
+
+/// Consecutive chunks of one source sent to the model together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Passage {
+    pub chunks: RangeInclusive<usize>,
+    // position of its best chunk in the ranking, lower is more relevant
+    pub rank: usize,
+    best_chunk: usize,
+}
+
+/// Expands every ranked chunk by `neighbour_count` on both sides without crossing into
+/// another source, merging the ranges that touch. `source_starts` holds the flat index of
+/// each source's first chunk, in order. Passages come out most relevant first.
+pub fn passages(
+    scores: &[ChunkScore],
+    source_starts: &[usize],
+    chunk_count: usize,
+    neighbour_count: usize,
+) -> Vec<Passage> {
+    let mut ranges = scores
+        .iter()
+        .enumerate()
+        .filter(|(_, score)| score.index < chunk_count)
+        .map(|(rank, score)| {
+            let source = source_starts.partition_point(|start| *start <= score.index);
+            let source_start = source
+                .checked_sub(1)
+                .map(|source| source_starts[source])
+                .unwrap_or_default();
+            let source_end = source_starts
+                .get(source)
+                .map(|next_start| next_start - 1)
+                .unwrap_or(chunk_count - 1);
+
+            let start = score
+                .index
+                .saturating_sub(neighbour_count)
+                .max(source_start);
+            let end = (score.index + neighbour_count).min(source_end);
+            (
+                source,
+                Passage {
+                    chunks: start..=end,
+                    rank,
+                    best_chunk: score.index,
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    ranges.sort_by_key(|(source, passage)| (*source, *passage.chunks.start()));
+
+    let mut passages: Vec<(usize, Passage)> = vec![];
+    for (source, passage) in ranges {
+        match passages.last_mut() {
+            Some((last_source, last))
+                if *last_source == source && *passage.chunks.start() <= last.chunks.end() + 1 =>
+            {
+                let end = *last.chunks.end().max(passage.chunks.end());
+                last.chunks = *last.chunks.start()..=end;
+                if passage.rank < last.rank {
+                    last.rank = passage.rank;
+                    last.best_chunk = passage.best_chunk;
+                }
+            }
+            _ => passages.push((source, passage)),
+        }
+    }
+
+    let mut passages = passages
+        .into_iter()
+        .map(|(_, passage)| passage)
+        .collect::<Vec<_>>();
+    passages.sort_by_key(|passage| passage.rank);
+    passages
+}
+
+/// Joins the passages, most relevant first, until `token_budget` is spent. A passage that
+/// doesn't fit is cut down to its best chunk, and skipped if even that doesn't fit.
+/// Returns the context and the chunks it's made of.
+pub fn assemble(
+    contents: &[String],
+    passages: &[Passage],
+    token_budget: usize,
+) -> (String, Vec<usize>) {
+    let mut context = String::new();
+    let mut chunks = vec![];
+    let mut token_count = 0;
+
+    for passage in passages {
+        let text = passage
+            .chunks
+            .clone()
+            .map(|i| contents[i].as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let tokens = count_tokens(&text) + 1;
+
+        let (text, tokens) = if token_count + tokens <= token_budget {
+            chunks.extend(passage.chunks.clone());
+            (text, tokens)
+        } else {
+            let text = contents[passage.best_chunk].clone();
+            let tokens = count_tokens(&text) + 1;
+            if token_count + tokens > token_budget {
+                continue;
+            }
+            chunks.push(passage.best_chunk);
+            (text, tokens)
+        };
+
+        token_count += tokens;
+        context.push_str(&text);
+        context.push_str("\n\n");
+    }
+
+    (context, chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_fusion() {
+        let weights = FusionWeights {
+            vector: 1.0,
+            lexical: 1.0,
+            k: 60.0,
+            page_boost: 1.5,
+        };
+        let vector_hits = vec![(3, 0.9), (1, 0.8), (2, 0.7)];
+        let lexical_hits = vec![(2, 12.0), (5, 4.0)];
+
+        let fused = fuse(&vector_hits, &lexical_hits, &weights, 10);
+        // found by both, so it beats the top vector hit
+        assert_eq!(fused[0].index, 2);
+        assert_eq!(fused[0].vector_score, Some(0.7));
+        assert_eq!(fused[0].lexical_score, Some(12.0));
+        assert_eq!(fused[1].index, 3);
+        assert_eq!(fused.len(), 4);
+
+        let lexical_only = FusionWeights {
+            vector: 0.0,
+            ..weights
+        };
+        let fused = fuse(&vector_hits, &lexical_hits, &lexical_only, 1);
+        assert_eq!(fused[0].index, 2);
+        assert_eq!(fused.len(), 1);
+    }
+
+    #[test]
+    fn page_boost() {
+        let weights = FusionWeights {
+            vector: 1.0,
+            lexical: 1.0,
+            k: 60.0,
+            page_boost: 1.5,
+        };
+        let fused = fuse(&[(0, 0.9), (1, 0.8), (2, 0.7)], &[], &weights, 10);
+
+        let boosted = boost(fused, |index| index == 1, &weights, 2);
+        assert_eq!(boosted[0].index, 1);
+        assert!(boosted[0].boosted);
+        assert_eq!(boosted[1].index, 0);
+        assert_eq!(boosted.len(), 2);
+    }
+
+    #[test]
+    fn mmr_spreads_sources() {
+        // chunks 0..=2 repeat one paragraph of the first source, 3 is another source
+        let embeddings = vec![
+            vec![1.0, 0.0],
+            vec![1.0, 0.01],
+            vec![0.99, 0.0],
+            vec![0.6, 0.8],
+        ];
+        let embeddings = embeddings.iter().collect::<Vec<_>>();
+        let scores = [0.04, 0.039, 0.038, 0.03]
+            .iter()
+            .enumerate()
+            .map(|(index, fused_score)| ChunkScore {
+                index,
+                fused_score: *fused_score,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let source_of = |index: usize| (index == 3) as usize;
+
+        let relevance_only = Diversity {
+            lambda: 1.0,
+            max_per_source: None,
+        };
+        let picked = diversify(scores.clone(), &embeddings, source_of, &relevance_only, 2);
+        assert_eq!(
+            source_count(picked.iter().map(|score| score.index), source_of),
+            1
+        );
+
+        let balanced = Diversity {
+            lambda: 0.5,
+            max_per_source: None,
+        };
+        let picked = diversify(scores.clone(), &embeddings, source_of, &balanced, 2);
+        assert_eq!(
+            picked.iter().map(|score| score.index).collect::<Vec<_>>(),
+            vec![0, 3]
+        );
+
+        let capped = Diversity {
+            lambda: 1.0,
+            max_per_source: Some(1),
+        };
+        let picked = diversify(scores, &embeddings, source_of, &capped, 3);
+        assert_eq!(
+            picked.iter().map(|score| score.index).collect::<Vec<_>>(),
+            vec![0, 3]
+        );
+    }
+
+    #[test]
+    fn explain_candidates() {
+        let contents = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let candidates = vec![
+            ChunkScore {
+                index: 2,
+                fused_score: 0.03,
+                ..Default::default()
+            },
+            ChunkScore {
+                index: 0,
+                fused_score: 0.02,
+                ..Default::default()
+            },
+        ];
+        let selected = vec![ChunkScore {
+            rerank_score: Some(0.9),
+            ..candidates[1].clone()
+        }];
+
+        let explained = explain(&candidates, &selected, &[0, 1], &contents, |index| {
+            format!("https://thepagebot.com/{}", index)
+        });
+        assert_eq!(explained[0].rank, None);
+        assert!(!explained[0].in_context);
+        assert_eq!(explained[0].content, "c");
+        assert_eq!(explained[1].fused_rank, 1);
+        assert_eq!(explained[1].rank, Some(0));
+        assert!(explained[1].in_context);
+        assert_eq!(explained[1].score.rerank_score, Some(0.9));
+        assert_eq!(explained[1].source_uri, "https://thepagebot.com/0");
+    }
+
+    // prefers shorter chunks, enough to tell the order changed
+    struct LengthReranker;
+
+    impl Reranker for LengthReranker {
+        fn rerank(&self, _query: &str, candidates: &[&str]) -> eyre::Result<Vec<f32>> {
+            Ok(candidates
+                .iter()
+                .map(|candidate| -(candidate.len() as f32))
+                .collect())
+        }
+    }
+
+    struct FailingReranker;
+
+    impl Reranker for FailingReranker {
+        fn rerank(&self, _query: &str, _candidates: &[&str]) -> eyre::Result<Vec<f32>> {
+            Err(eyre::eyre!("model unavailable"))
+        }
+    }
+
+    #[test]
+    fn rerank_keeps_top_n() {
+        let contents = vec![
+            "a long chunk about pricing".to_string(),
+            "short".to_string(),
+            "a medium chunk".to_string(),
+        ];
+        let scores = (0..3)
+            .map(|index| ChunkScore {
+                index,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let reranked = rerank(&LengthReranker, "pricing", &contents, scores.clone(), 2);
+        assert_eq!(
+            reranked.iter().map(|score| score.index).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(reranked[0].rerank_score, Some(-5.0));
+
+        // the fused order is kept, still cut to top_n
+        let fallback = rerank(&FailingReranker, "pricing", &contents, scores, 2);
+        assert_eq!(
+            fallback.iter().map(|score| score.index).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(fallback[0].rerank_score, None);
+    }
+
+    fn ranked(indexes: &[usize]) -> Vec<ChunkScore> {
+        indexes
+            .iter()
+            .map(|index| ChunkScore {
+                index: *index,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn passages_merge_within_sources() {
+        // two sources: chunks 0..=5 and 6..=9
+        let passages = passages(&ranked(&[7, 2, 4, 5]), &[0, 6], 10, 1);
+        assert_eq!(
+            passages
+                .iter()
+                .map(|passage| (passage.chunks.clone(), passage.rank))
+                .collect::<Vec<_>>(),
+            // 7 doesn't reach back into the first source, 2, 4 and 5 touch
+            vec![(6..=8, 0), (1..=5, 1)]
+        );
+    }
+
+    #[test]
+    fn assemble_stops_at_budget() {
+        let contents = (0..6)
+            .map(|i| format!("chunk number {} of the pricing page", i))
+            .collect::<Vec<_>>();
+        let passages = passages(&ranked(&[4, 0]), &[0], contents.len(), 1);
+
+        let (context, chunks) = assemble(&contents, &passages, 1000);
+        assert_eq!(chunks, vec![3, 4, 5, 0, 1]);
+        // the best passage leads
+        assert!(context.starts_with("chunk number 3"));
+        assert!(context.contains("chunk number 1"));
+
+        // only the best passage fits
+        let chunk_tokens = count_tokens(&contents[0]) + 1;
+        let (context, _) = assemble(&contents, &passages, chunk_tokens * 3 + 1);
+        assert!(context.contains("chunk number 5"));
+        assert!(!context.contains("chunk number 0"));
+
+        // not even one passage fits, its best chunk does
+        let (context, chunks) = assemble(&contents, &passages, chunk_tokens);
+        assert_eq!(chunks, vec![4]);
+        assert_eq!(context.trim(), contents[4]);
+
+        assert!(assemble(&contents, &passages, 0).0.is_empty());
+    }
+}
@@ -0,0 +1,25 @@
+use crate::{
+    jwt::UserContext,
+    routes::JsonResponse,
+    types::credential::{CredentialOutput, StoredCredential},
+};
+use axum::Json;
+use reqwest::StatusCode;
+
+pub async fn main(UserContext { user }: UserContext) -> JsonResponse<Vec<CredentialOutput>> {
+    let credentials = StoredCredential::by_user(user.id).map_err(|e| {
+        log::error!("Failed to get credentials: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let credentials = credentials
+        .into_iter()
+        .map(CredentialOutput::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            log::error!("Failed to decrypt credentials: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((StatusCode::OK, Json(credentials)))
+}
@@ -6,6 +6,11 @@ export_route!(
     get,
     me,
     stats,
+    ready,
+    sources,
+    source,
+    pinned_answers,
+    credentials,
     // (checkout_session, "checkout_session"),
     (benchmark, "loaderio-f6e0730790630a9271de186889ff3c19/")
 );
@@ -0,0 +1,24 @@
+use crate::{
+    jwt::UserContext,
+    routes::JsonResponse,
+    types::pinned_answer::{PinnedAnswer, PinnedAnswerOutput},
+};
+use axum::Json;
+use reqwest::StatusCode;
+
+pub async fn main(UserContext { user }: UserContext) -> JsonResponse<Vec<PinnedAnswerOutput>> {
+    let pinned_answers = PinnedAnswer::by_user(user.id).map_err(|e| {
+        log::error!("Failed to get pinned answers: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(
+            pinned_answers
+                .into_iter()
+                .map(PinnedAnswerOutput::from)
+                .collect(),
+        ),
+    ))
+}
@@ -0,0 +1,12 @@
+use reqwest::StatusCode;
+
+use crate::embed_pool::EMBED_POOL;
+
+/// Readiness probe, the server only takes messages once the embedding pool is warmed up.
+pub async fn main() -> StatusCode {
+    if EMBED_POOL.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -0,0 +1,27 @@
+use crate::{
+    jwt::UserContext,
+    routes::JsonResponse,
+    types::source::{Source, SourceOutput},
+};
+use axum::{extract::Query, Json};
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Params {
+    uri: String,
+}
+
+pub async fn main(
+    UserContext { user }: UserContext,
+    Query(Params { uri }): Query<Params>,
+) -> JsonResponse<SourceOutput> {
+    let source = Source::by_user_uri(user.id, &uri)
+        .map_err(|e| {
+            log::error!("Failed to get source: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((StatusCode::OK, Json(source.into())))
+}
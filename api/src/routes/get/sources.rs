@@ -0,0 +1,19 @@
+use crate::{
+    jwt::UserContext,
+    routes::JsonResponse,
+    types::source::{Source, SourceOutput},
+};
+use axum::Json;
+use reqwest::StatusCode;
+
+pub async fn main(UserContext { user }: UserContext) -> JsonResponse<Vec<SourceOutput>> {
+    let sources = Source::by_user(user.id).map_err(|e| {
+        log::error!("Failed to get sources: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(sources.into_iter().map(SourceOutput::from).collect()),
+    ))
+}
@@ -1,4 +1,8 @@
-use crate::{routes::JsonResponse, stats::*};
+use crate::{
+    embed_pool::{EmbedPoolStats, EMBED_POOL},
+    routes::JsonResponse,
+    stats::*,
+};
 use axum::Json;
 use reqwest::StatusCode;
 use serde::Serialize;
@@ -8,6 +12,7 @@ pub struct Stats {
     pub user_count: u32,
     pub message_count: String,
     pub page_count: String,
+    pub embed_pool: EmbedPoolStats,
 }
 
 pub async fn main() -> JsonResponse<Stats> {
@@ -21,6 +26,7 @@ pub async fn main() -> JsonResponse<Stats> {
             page_count: PAGE_COUNT
                 .load(std::sync::atomic::Ordering::Relaxed)
                 .to_string(),
+            embed_pool: EMBED_POOL.stats(),
         }),
     ))
 }
@@ -0,0 +1,53 @@
+use axum::{
+    extract::{BodyStream, Query},
+    Json,
+};
+use futures::StreamExt;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    jwt::UserContext,
+    routes::JsonResponse,
+    types::bundle::{Bundle, BundleOutput, MAX_BUNDLE_SIZE},
+};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Params {
+    name: String,
+}
+
+/// Takes the zip archive as the raw request body.
+pub async fn main(
+    UserContext { user }: UserContext,
+    Query(Params { name }): Query<Params>,
+    mut body: BodyStream,
+) -> JsonResponse<BundleOutput> {
+    let mut archive = vec![];
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| {
+            log::error!("Failed to read bundle: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+
+        if archive.len() + chunk.len() > MAX_BUNDLE_SIZE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        archive.extend_from_slice(&chunk);
+    }
+
+    let (bundle, skipped) = Bundle::import(user.id, name, archive).await.map_err(|e| {
+        log::error!("Failed to import bundle: {}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(BundleOutput {
+            source_count: bundle.uris.len(),
+            name: bundle.name,
+            version: bundle.version,
+            skipped,
+        }),
+    ))
+}
@@ -0,0 +1,67 @@
+use axum::Json;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    chat_provider::{is_allowed_api_base, ApiKey, ChatModel, ChatSettingsOutput, ProviderConfig},
+    jwt::UserContext,
+    routes::JsonResponse,
+};
+
+#[derive(Deserialize)]
+pub struct Request {
+    pub provider: ProviderConfig,
+    #[serde(default)]
+    pub models: Vec<ChatModel>,
+    // replaces the stored key, which is kept when omitted
+    pub api_key: Option<String>,
+}
+
+pub async fn main(
+    UserContext { mut user }: UserContext,
+    Json(request): Json<Request>,
+) -> JsonResponse<ChatSettingsOutput> {
+    if request
+        .models
+        .iter()
+        .any(|model| model.name.trim().is_empty() || model.context_window == 0)
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // the server calls this host, so internal addresses need the operator's allow-list
+    if let Some(api_base) = request.provider.api_base() {
+        if !is_allowed_api_base(api_base).await {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    // a stored key is only ever sent to the endpoint it was given for
+    if std::mem::discriminant(&request.provider) != std::mem::discriminant(&user.chat.provider)
+        || request.provider.api_base() != user.chat.provider.api_base()
+    {
+        user.chat.api_key = None;
+    }
+
+    let api_key = request.api_key.filter(|api_key| !api_key.is_empty());
+    if request.provider.needs_api_key() && api_key.is_none() && user.chat.api_key.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if let Some(api_key) = api_key {
+        user.chat.api_key = Some(ApiKey::new(&api_key).map_err(|e| {
+            log::error!("Failed to encrypt api key: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?);
+    }
+    user.chat.provider = request.provider;
+    user.chat.models = request.models;
+    user.chat.models.sort_by_key(|model| model.context_window);
+
+    let user = user.save().map_err(|e| {
+        log::error!("Failed to save user: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((StatusCode::OK, Json(user.chat.into())))
+}
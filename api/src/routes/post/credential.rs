@@ -0,0 +1,30 @@
+use axum::Json;
+use reqwest::StatusCode;
+
+use crate::{
+    jwt::UserContext,
+    routes::JsonResponse,
+    types::credential::{CredentialInput, CredentialOutput, StoredCredential},
+};
+
+pub async fn main(
+    UserContext { user }: UserContext,
+    Json(input): Json<CredentialInput>,
+) -> JsonResponse<CredentialOutput> {
+    let credential = StoredCredential::new(input).map_err(|e| {
+        log::error!("Failed to create credential: {}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let credential = credential.save(user.id).map_err(|e| {
+        log::error!("Failed to save credential: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let credential = CredentialOutput::try_from(credential).map_err(|e| {
+        log::error!("Failed to decrypt credential: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((StatusCode::OK, Json(credential)))
+}
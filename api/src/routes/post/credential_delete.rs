@@ -0,0 +1,26 @@
+use axum::Json;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{jwt::UserContext, routes::GenericResponse, types::credential::StoredCredential};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Request {
+    id: String,
+}
+
+pub async fn main(
+    UserContext { user }: UserContext,
+    Json(Request { id }): Json<Request>,
+) -> GenericResponse<()> {
+    let deleted = StoredCredential::delete(user.id, &id).map_err(|e| {
+        log::error!("Failed to delete credential: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if deleted {
+        Ok((StatusCode::OK, ()))
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use axum::Json;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    embed_pool::{EmbedPoolError, EMBED_POOL},
+    jwt::UserContext,
+    notification::Notification,
+    retrieval::{CandidateChunk, DiversityStats},
+    types::{history_item::HistoryItem, message::Message},
+};
+
+use super::message::MessageError;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Request {
+    message: Message,
+    #[serde(default)]
+    history: Vec<HistoryItem>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Response {
+    query: String,
+    rewritten_query: Option<String>,
+    best_similarity: Option<f32>,
+    not_found: bool,
+    pinned_answer: Option<String>,
+    diversity: DiversityStats,
+    context: String,
+    candidates: Vec<CandidateChunk>,
+}
+
+/// Runs a visitor's message against the account's cached sources without asking the model,
+/// showing how every candidate chunk was scored and whether it reached the context.
+/// Nothing is fetched, linked or sent.
+pub async fn main(
+    UserContext { user }: UserContext,
+    Json(Request {
+        mut message,
+        history,
+    }): Json<Request>,
+) -> Result<(StatusCode, Json<Response>), MessageError> {
+    // explaining embeds the query like a message does, so it sheds load the same way
+    if !EMBED_POOL.is_ready() || EMBED_POOL.is_saturated() {
+        return Err(MessageError::Overloaded);
+    }
+
+    message.user_id = user.id;
+    message.explain = true;
+    message.read_only = true;
+
+    let notification = Arc::new(Notification::silent(user.email.clone()));
+    let evaluated_message = message
+        .evaluate(&user, &history, notification)
+        .await
+        .map_err(|e| match e.downcast_ref::<EmbedPoolError>() {
+            Some(EmbedPoolError::Saturated | EmbedPoolError::Timeout) => {
+                log::warn!("Embedding pool overloaded: {}", e);
+                MessageError::Overloaded
+            }
+            _ => {
+                log::error!("Failed to explain message: {}", e);
+                MessageError::Status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(Response {
+            query: evaluated_message.query,
+            rewritten_query: evaluated_message.perf.rewritten_query,
+            best_similarity: evaluated_message.perf.best_similarity,
+            not_found: evaluated_message.not_found,
+            pinned_answer: evaluated_message.pinned_answer,
+            diversity: evaluated_message.perf.diversity,
+            context: evaluated_message.merged_sources,
+            candidates: evaluated_message.candidates.unwrap_or_default(),
+        }),
+    ))
+}
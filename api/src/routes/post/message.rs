@@ -1,6 +1,8 @@
 use std::sync::Arc;
 
-use crate::llm_retrieval::{get_response, get_response_stream, Operation};
+use crate::chat_provider::Chat;
+use crate::embed_pool::{EmbedPoolError, EMBED_POOL, RETRY_AFTER_SECS};
+use crate::llm_retrieval::{get_response, get_response_stream, Operation, OperationStream};
 use crate::types::user::FREE_MESSAGE_COUNT;
 use crate::{
     notification::{Notification, NotificationType},
@@ -16,7 +18,8 @@ use crate::{
 
 use axum::{
     extract::Host,
-    response::{sse::Event, Sse},
+    http::header::RETRY_AFTER,
+    response::{sse::Event, IntoResponse, Sse},
     Json,
 };
 use eyre::Result;
@@ -41,6 +44,31 @@ pub enum Response {
     None(&'static str),
 }
 
+pub enum MessageError {
+    Status(StatusCode),
+    // the embedding pool can't take more work, clients should retry later
+    Overloaded,
+}
+
+impl From<StatusCode> for MessageError {
+    fn from(status: StatusCode) -> Self {
+        MessageError::Status(status)
+    }
+}
+
+impl IntoResponse for MessageError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            MessageError::Status(status) => status.into_response(),
+            MessageError::Overloaded => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            )
+                .into_response(),
+        }
+    }
+}
+
 impl From<Operation> for Response {
     fn from(operation: Operation) -> Self {
         match operation {
@@ -52,22 +80,15 @@ impl From<Operation> for Response {
     }
 }
 
-impl From<&String> for Response {
-    fn from(s: &String) -> Self {
-        if s.contains("_N") {
-            Response::NotFound("")
-        } else if s.contains("_E") {
-            Response::Email("")
-        } else {
-            Response::Chunk(s.clone())
-        }
-    }
-}
-
 pub async fn main(
     Host(host): Host,
     Json(Request { message, history }): Json<Request>,
-) -> Result<Sse<impl Stream<Item = Result<Event>>>, StatusCode> {
+) -> Result<Sse<impl Stream<Item = Result<Event>>>, MessageError> {
+    // shed load before doing any work for the message
+    if !EMBED_POOL.is_ready() || EMBED_POOL.is_saturated() {
+        return Err(MessageError::Overloaded);
+    }
+
     let mut user = User::by_id(message.user_id)
         .map_err(|e| {
             log::error!("Failed to get user: {}", e);
@@ -80,7 +101,7 @@ pub async fn main(
 
     if let Some(allowed_domains) = &user.allowed_domains {
         if !allowed_domains.iter().any(|domain| host.contains(domain)) {
-            return Err(StatusCode::FORBIDDEN);
+            return Err(StatusCode::FORBIDDEN.into());
         }
     }
 
@@ -91,28 +112,51 @@ pub async fn main(
                 .send(NotificationType::MaxLimitReached)
                 .await;
         });
-        return Err(StatusCode::FORBIDDEN);
+        return Err(StatusCode::FORBIDDEN.into());
     }
 
     let notification = Arc::new(Notification::new(user.email.clone()));
     let total_time = std::time::Instant::now();
 
-    let evaluated_message = message.evaluate(notification.clone()).await.map_err(|e| {
-        log::error!("Failed to evaluate message: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let evaluated_message = message
+        .evaluate(&user, &history, notification.clone())
+        .await
+        .map_err(|e| match e.downcast_ref::<EmbedPoolError>() {
+            Some(EmbedPoolError::Saturated | EmbedPoolError::Timeout) => {
+                log::warn!("Embedding pool overloaded: {}", e);
+                MessageError::Overloaded
+            }
+            _ => {
+                log::error!("Failed to evaluate message: {}", e);
+                MessageError::Status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        })?;
 
     log::info!("Evaluated message: {:?}", evaluated_message);
 
+    let chat = Chat::for_user(&user).map_err(|e| {
+        log::error!("Failed to configure chat provider: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
     let query = evaluated_message.query.clone();
     let gen_notification = notification.clone();
     let perf = evaluated_message.perf.clone();
-    let response_stream = get_response_stream(&evaluated_message, history)
-        .await
-        .map_err(|e| {
-            log::error!("Failed to get response stream: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    let response_stream: OperationStream = match evaluated_message.pinned_answer.clone() {
+        Some(answer) => Box::new(futures::stream::once(async move {
+            Ok(Operation::Answer((answer, Default::default())))
+        })),
+        // off-topic, answered without a model request
+        None if evaluated_message.not_found => Box::new(futures::stream::once(async {
+            Ok(Operation::NotFound(Default::default()))
+        })),
+        None => get_response_stream(&chat, &evaluated_message, history)
+            .await
+            .map_err(|e| {
+                log::error!("Failed to get response stream: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?,
+    };
 
     let mut response_stream = Box::into_pin(response_stream);
 
@@ -3,6 +3,22 @@ use crate::routes::Route;
 use axum::routing::post;
 
 export_route!(
-    post, message, // stripe_webhook,
-    ls_webhook, email, domains, login, jwt,
+    post,
+    message, // stripe_webhook,
+    ls_webhook,
+    email,
+    domains,
+    login,
+    jwt,
+    source_refresh,
+    source_delete,
+    pinned_answer,
+    pinned_answer_delete,
+    bundle,
+    credential,
+    credential_delete,
+    renderer,
+    page_rules,
+    chat_settings,
+    explain,
 );
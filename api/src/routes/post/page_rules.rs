@@ -0,0 +1,21 @@
+use axum::Json;
+use reqwest::StatusCode;
+
+use crate::{jwt::UserContext, routes::JsonResponse, types::page_rule::PageRule};
+
+pub async fn main(
+    UserContext { mut user }: UserContext,
+    Json(page_rules): Json<Vec<PageRule>>,
+) -> JsonResponse<Vec<PageRule>> {
+    if page_rules.iter().any(|rule| rule.pages.is_empty()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    user.page_rules = page_rules;
+    let user = user.save().map_err(|e| {
+        log::error!("Failed to save user: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((StatusCode::OK, Json(user.page_rules)))
+}
@@ -0,0 +1,25 @@
+use axum::Json;
+use reqwest::StatusCode;
+
+use crate::{
+    jwt::UserContext,
+    routes::JsonResponse,
+    types::pinned_answer::{PinnedAnswer, PinnedAnswerInput, PinnedAnswerOutput},
+};
+
+pub async fn main(
+    UserContext { user }: UserContext,
+    Json(input): Json<PinnedAnswerInput>,
+) -> JsonResponse<PinnedAnswerOutput> {
+    let pinned_answer = PinnedAnswer::new(input).await.map_err(|e| {
+        log::error!("Failed to create pinned answer: {}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let pinned_answer = pinned_answer.save(user.id).map_err(|e| {
+        log::error!("Failed to save pinned answer: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((StatusCode::OK, Json(pinned_answer.into())))
+}
@@ -0,0 +1,23 @@
+use axum::Json;
+use reqwest::StatusCode;
+
+use crate::{jwt::UserContext, renderer::RendererConfig, routes::JsonResponse};
+
+pub async fn main(
+    UserContext { mut user }: UserContext,
+    Json(renderer): Json<RendererConfig>,
+) -> JsonResponse<RendererConfig> {
+    if let RendererConfig::SelfHosted { endpoint } = &renderer {
+        if reqwest::Url::parse(endpoint).is_err() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    user.renderer = renderer;
+    let user = user.save().map_err(|e| {
+        log::error!("Failed to save user: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((StatusCode::OK, Json(user.renderer)))
+}
@@ -0,0 +1,31 @@
+use axum::Json;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{jwt::UserContext, routes::GenericResponse, types::source::Source};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Request {
+    uri: String,
+}
+
+pub async fn main(
+    UserContext { user }: UserContext,
+    Json(Request { uri }): Json<Request>,
+) -> GenericResponse<()> {
+    let owned = Source::is_owned_by(&uri, user.id).map_err(|e| {
+        log::error!("Failed to get user sources: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if !owned {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Source::unlink_user(user.id, &uri).map_err(|e| {
+        log::error!("Failed to unlink source: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((StatusCode::OK, ()))
+}
@@ -0,0 +1,47 @@
+use axum::Json;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    jwt::UserContext,
+    routes::JsonResponse,
+    types::{
+        credential::AccountCredentials,
+        source::{Source, SourceError, SourceOutput},
+    },
+};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Request {
+    uri: String,
+}
+
+pub async fn main(
+    UserContext { user }: UserContext,
+    Json(Request { uri }): Json<Request>,
+) -> JsonResponse<SourceOutput> {
+    let source = Source::by_user_uri(user.id, &uri)
+        .map_err(|e| {
+            log::error!("Failed to get source: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let credentials = AccountCredentials::for_url(user.id, &source.chunks.url).map_err(|e| {
+        log::error!("Failed to get source credentials: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let source = source.refresh(credentials, &user.renderer).await.map_err(|e| match e {
+        SourceError::ContentEmpty(url) => {
+            log::error!("Refreshed source is empty: {}", url);
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        SourceError::Default(e) => {
+            log::error!("Failed to refresh source: {}", e);
+            StatusCode::BAD_GATEWAY
+        }
+    })?;
+
+    Ok((StatusCode::OK, Json(source.into())))
+}
@@ -0,0 +1,295 @@
+use std::io::{Cursor, Read};
+
+use eyre::Result;
+use futures::{stream, StreamExt, TryStreamExt};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+use crate::db::DB;
+
+use super::source::{Chunks, FetchStatus, Source};
+
+pub const MAX_BUNDLE_SIZE: usize = 50 * 1024 * 1024; // 50 MB
+const MAX_BUNDLE_FILES: usize = 2000;
+// decompressed, the sizes in the zip headers can't be trusted
+#[cfg(not(test))]
+const MAX_FILE_BYTES: u64 = 10 * 1024 * 1024; // 10 MB
+#[cfg(not(test))]
+const MAX_UNPACKED_BYTES: u64 = 200 * 1024 * 1024; // 200 MB
+#[cfg(test)]
+const MAX_FILE_BYTES: u64 = 10 * 1024;
+#[cfg(test)]
+const MAX_UNPACKED_BYTES: u64 = 200 * 1024;
+// files embedded at once, an import leaves room in the pool for messages
+const EMBED_CONCURRENCY: usize = 4;
+
+/// A zip archive of documentation indexed as one versioned set of sources.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Bundle {
+    pub user_id: u64,
+    pub name: String,
+    pub version: u32,
+    pub uris: Vec<String>,
+    pub created_at: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BundleOutput {
+    pub name: String,
+    pub version: u32,
+    pub source_count: usize,
+    pub skipped: Vec<String>,
+}
+
+lazy_static! {
+    static ref MARKDOWN_LINK: Regex = Regex::new(r"\]\(([^)\s]+)\)").unwrap();
+    static ref HTML_LINK: Regex = Regex::new(r#"(href|src)="([^"]+)""#).unwrap();
+}
+
+impl Bundle {
+    pub fn key(&self) -> String {
+        Self::id(self.user_id, &self.name)
+    }
+
+    fn id(user_id: u64, name: &str) -> String {
+        format!("{}:{}", user_id, name)
+    }
+
+    pub fn by_user(user_id: u64) -> Result<Vec<Bundle>> {
+        DB.bundles(&format!("{}:", user_id))
+    }
+
+    /// All the sources of the account's current bundle versions.
+    pub fn sources(user_id: u64) -> Result<Vec<Source>> {
+        let sources = Self::by_user(user_id)?
+            .into_iter()
+            .flat_map(|bundle| bundle.uris)
+            .filter_map(|uri| Source::by_url(&uri).ok().flatten())
+            .collect();
+        Ok(sources)
+    }
+
+    // the upload id keeps concurrent uploads of a name apart, versions are only known on save
+    fn uri_prefix(user_id: u64, name: &str, upload_id: &str) -> String {
+        format!("bundle://{}/{}@{}/", user_id, name, upload_id)
+    }
+
+    /// Indexes every supported file of the archive as a new version of the bundle,
+    /// returning it along with the paths that were skipped. The version is assigned
+    /// when the bundle replaces the previous one.
+    pub async fn import(
+        user_id: u64,
+        name: String,
+        archive: Vec<u8>,
+    ) -> Result<(Self, Vec<String>)> {
+        if name.is_empty() || name.contains(['/', ':', '@']) {
+            return Err(eyre::eyre!("Invalid bundle name: {}", name));
+        }
+
+        let uri_prefix = Self::uri_prefix(user_id, &name, &nanoid::nanoid!());
+
+        let files = read_archive(archive)?;
+        let mut skipped = vec![];
+        let mut documents = vec![];
+        for (path, bytes) in files {
+            // links are resolved before extraction, since the html extractor drops the markup around them
+            let bytes = match String::from_utf8(bytes) {
+                Ok(text) => resolve_relative_links(&text, &path, &uri_prefix).into_bytes(),
+                Err(e) => e.into_bytes(),
+            };
+
+            match Source::extract_file(&path, &bytes) {
+                Some(Ok(content)) if !content.trim().is_empty() => {
+                    documents.push((format!("{}{}", uri_prefix, path), content));
+                }
+                Some(Err(e)) => {
+                    log::error!("Failed to extract bundle file {}: {}", path, e);
+                    skipped.push(path);
+                }
+                _ => skipped.push(path),
+            }
+        }
+
+        if documents.is_empty() {
+            return Err(eyre::eyre!("Bundle has no supported files"));
+        }
+
+        let created_at = chrono::Utc::now().timestamp() as u32;
+        let pending_sources = documents.into_iter().map(|(uri, content)| async move {
+            Ok::<_, eyre::Report>(Source {
+                chunks: Chunks::new(content, &uri).await?,
+                uri,
+                // bundle sources live until the bundle is replaced
+                expires: u32::MAX - created_at,
+                created_at,
+                fetch_status: FetchStatus::Ok,
+                tags: Default::default(),
+            })
+        });
+        let sources = stream::iter(pending_sources)
+            .buffered(EMBED_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let bundle = Self {
+            user_id,
+            name,
+            version: 0,
+            uris: sources.iter().map(|source| source.uri.clone()).collect(),
+            created_at,
+        };
+
+        let bundle = DB.bundle_replace(bundle, sources)?;
+        Ok((bundle, skipped))
+    }
+}
+
+fn read_archive(archive: Vec<u8>) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(archive))?;
+    if zip.len() > MAX_BUNDLE_FILES {
+        return Err(eyre::eyre!(
+            "Bundle has more than {} files",
+            MAX_BUNDLE_FILES
+        ));
+    }
+
+    let mut files = vec![];
+    let mut unpacked = 0;
+    for index in 0..zip.len() {
+        let mut file = zip.by_index(index)?;
+        // enclosed_name drops entries that would escape the archive root
+        let path = match file.enclosed_name() {
+            Some(path) if file.is_file() => path.to_string_lossy().replace('\\', "/"),
+            _ => continue,
+        };
+
+        let mut bytes = vec![];
+        (&mut file)
+            .take(MAX_FILE_BYTES + 1)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > MAX_FILE_BYTES {
+            return Err(eyre::eyre!(
+                "{} is larger than {} bytes unpacked",
+                path,
+                MAX_FILE_BYTES
+            ));
+        }
+
+        unpacked += bytes.len() as u64;
+        if unpacked > MAX_UNPACKED_BYTES {
+            return Err(eyre::eyre!(
+                "Bundle is larger than {} bytes unpacked",
+                MAX_UNPACKED_BYTES
+            ));
+        }
+        files.push((path, bytes));
+    }
+    Ok(files)
+}
+
+/// Rewrites relative markdown and html links against the file's location in the bundle.
+fn resolve_relative_links(content: &str, path: &str, uri_prefix: &str) -> String {
+    let resolve = |link: &str| -> Option<String> {
+        if link.starts_with('#') || link.contains(':') {
+            return None;
+        }
+
+        let (link, fragment) = match link.split_once('#') {
+            Some((link, fragment)) => (link, format!("#{}", fragment)),
+            None => (link, String::new()),
+        };
+
+        let mut segments = if link.starts_with('/') {
+            vec![]
+        } else {
+            path.split('/').collect::<Vec<_>>()
+        };
+        // drop the file name, links are relative to its directory
+        segments.pop();
+
+        for segment in link.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                segment => segments.push(segment),
+            }
+        }
+
+        Some(format!("{}{}{}", uri_prefix, segments.join("/"), fragment))
+    };
+
+    let content =
+        MARKDOWN_LINK.replace_all(content, |captures: &Captures| match resolve(&captures[1]) {
+            Some(resolved) => format!("]({})", resolved),
+            None => captures[0].to_string(),
+        });
+
+    HTML_LINK
+        .replace_all(&content, |captures: &Captures| {
+            match resolve(&captures[2]) {
+                Some(resolved) => format!("{}=\"{}\"", &captures[1], resolved),
+                None => captures[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_links() {
+        let prefix = "bundle://1/docs@2/";
+        let content = "See [setup](../setup.md#install), [api](./api/index.md) and [site](https://thepagebot.com).";
+        let resolved = resolve_relative_links(content, "guides/start.md", prefix);
+
+        assert_eq!(
+            resolved,
+            "See [setup](bundle://1/docs@2/setup.md#install), [api](bundle://1/docs@2/guides/api/index.md) and [site](https://thepagebot.com)."
+        );
+
+        let html =
+            r#"<a href="/pricing.html">Pricing</a> <a href="mailto:simdi@thepagebot.com">Mail</a>"#;
+        let resolved = resolve_relative_links(html, "guides/start.html", prefix);
+        assert_eq!(
+            resolved,
+            r#"<a href="bundle://1/docs@2/pricing.html">Pricing</a> <a href="mailto:simdi@thepagebot.com">Mail</a>"#
+        );
+    }
+
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (path, content) in files {
+            zip.start_file(*path, zip::write::FileOptions::default())
+                .expect("start file");
+            std::io::Write::write_all(&mut zip, content).expect("write file");
+        }
+        zip.finish().expect("finish").into_inner()
+    }
+
+    #[test]
+    fn unpacked_size_limits() {
+        let files = read_archive(archive(&[("docs/start.md", b"# Start")])).expect("files");
+        assert_eq!(
+            files,
+            vec![("docs/start.md".to_string(), b"# Start".to_vec())]
+        );
+
+        // zeros deflate to a few kilobytes
+        let bomb = vec![0; MAX_FILE_BYTES as usize + 1];
+        assert!(read_archive(archive(&[("bomb.md", &bomb)])).is_err());
+
+        let file = vec![0; MAX_FILE_BYTES as usize];
+        let files = (0..=MAX_UNPACKED_BYTES / MAX_FILE_BYTES)
+            .map(|i| (format!("{}.md", i), file.as_slice()))
+            .collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(path, content)| (path.as_str(), *content))
+            .collect::<Vec<_>>();
+        assert!(read_archive(archive(&files)).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use std::fmt::{Debug, Formatter};
+
+use eyre::Result;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto, db::DB};
+
+/// Credentials for fetching the sources under `url_prefix`, stored encrypted.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct StoredCredential {
+    pub id: String,
+    pub url_prefix: String,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct SourceCredentials {
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub basic_auth: Option<BasicAuth>,
+    pub bearer_token: Option<String>,
+    #[serde(default)]
+    pub cookies: Vec<(String, String)>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CredentialInput {
+    pub id: Option<String>,
+    pub url_prefix: String,
+    #[serde(flatten)]
+    pub credentials: SourceCredentials,
+}
+
+/// Only describes what kind of credentials are stored, never their values.
+#[derive(Debug, Serialize, Clone)]
+pub struct CredentialOutput {
+    pub id: String,
+    pub url_prefix: String,
+    pub header_names: Vec<String>,
+    pub basic_auth: bool,
+    pub bearer_token: bool,
+    pub cookie_names: Vec<String>,
+}
+
+/// Decrypted credentials along with the account they belong to.
+#[derive(Clone)]
+pub struct AccountCredentials {
+    pub user_id: u64,
+    pub credentials: SourceCredentials,
+}
+
+impl Debug for StoredCredential {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StoredCredential({})", self.url_prefix)
+    }
+}
+
+impl Debug for SourceCredentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SourceCredentials(<redacted>)")
+    }
+}
+
+impl Debug for AccountCredentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AccountCredentials({}, <redacted>)", self.user_id)
+    }
+}
+
+impl StoredCredential {
+    pub fn new(input: CredentialInput) -> Result<Self> {
+        if input.url_prefix.trim().is_empty() {
+            return Err(eyre::eyre!("url_prefix is required"));
+        }
+        if Url::parse(input.url_prefix.trim())?.host_str().is_none() {
+            return Err(eyre::eyre!("url_prefix needs a host"));
+        }
+
+        let (nonce, ciphertext) = crypto::encrypt(&serde_json::to_vec(&input.credentials)?)?;
+        Ok(Self {
+            id: input.id.unwrap_or_else(|| nanoid::nanoid!()),
+            url_prefix: input.url_prefix.trim().to_string(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub fn by_user(user_id: u64) -> Result<Vec<StoredCredential>> {
+        DB.credentials(user_id)
+    }
+
+    pub fn save(self, user_id: u64) -> Result<Self> {
+        let mut credentials = DB.credentials(user_id)?;
+        credentials.retain(|credential| credential.id != self.id);
+        credentials.push(self.clone());
+        DB.credentials_save(user_id, credentials)?;
+        Ok(self)
+    }
+
+    pub fn delete(user_id: u64, id: &str) -> Result<bool> {
+        let mut credentials = DB.credentials(user_id)?;
+        let count = credentials.len();
+        credentials.retain(|credential| credential.id != id);
+        let deleted = credentials.len() != count;
+        if deleted {
+            DB.credentials_save(user_id, credentials)?;
+        }
+        Ok(deleted)
+    }
+
+    pub fn decrypt(&self) -> Result<SourceCredentials> {
+        let plaintext = crypto::decrypt(&self.nonce, &self.ciphertext)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    /// The url must be on the prefix's scheme, host and port, and its path must continue the
+    /// prefix's path at a `/`, so `/docs` covers `/docs/a` but not `/docs-private`.
+    pub fn covers(&self, url: &str) -> bool {
+        let (prefix, url) = match (Url::parse(&self.url_prefix), Url::parse(url)) {
+            (Ok(prefix), Ok(url)) => (prefix, url),
+            _ => return false,
+        };
+
+        if prefix.scheme() != url.scheme()
+            || prefix.host_str().is_none()
+            || prefix.host_str() != url.host_str()
+            || prefix.port_or_known_default() != url.port_or_known_default()
+        {
+            return false;
+        }
+
+        let prefix_path = prefix.path();
+        let path = url.path();
+        prefix_path.ends_with('/') && path.starts_with(prefix_path)
+            || path == prefix_path
+            || path.starts_with(prefix_path) && path[prefix_path.len()..].starts_with('/')
+    }
+}
+
+impl AccountCredentials {
+    /// Picks the stored credential with the longest prefix covering the url.
+    pub fn matching(
+        stored: &[StoredCredential],
+        user_id: u64,
+        url: &str,
+    ) -> Result<Option<AccountCredentials>> {
+        let credential = stored
+            .iter()
+            .filter(|credential| credential.covers(url))
+            .max_by_key(|credential| credential.url_prefix.len());
+
+        match credential {
+            Some(credential) => Ok(Some(AccountCredentials {
+                user_id,
+                credentials: credential.decrypt()?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn for_url(user_id: u64, url: &str) -> Result<Option<AccountCredentials>> {
+        Self::matching(&StoredCredential::by_user(user_id)?, user_id, url)
+    }
+
+    pub fn apply(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let SourceCredentials {
+            headers,
+            basic_auth,
+            bearer_token,
+            cookies,
+        } = &self.credentials;
+
+        for (name, value) in headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        if let Some(BasicAuth { username, password }) = basic_auth {
+            request = request.basic_auth(username, password.as_ref());
+        }
+
+        if let Some(token) = bearer_token {
+            request = request.bearer_auth(token);
+        }
+
+        if !cookies.is_empty() {
+            let cookie = cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("; ");
+            request = request.header(reqwest::header::COOKIE, cookie);
+        }
+
+        request
+    }
+
+    /// Content fetched with an account's credentials is cached for that account only.
+    pub fn scope_uri(&self, url: &str) -> String {
+        format!("auth:{}:{}", self.user_id, url)
+    }
+}
+
+impl TryFrom<StoredCredential> for CredentialOutput {
+    type Error = eyre::Report;
+
+    fn try_from(credential: StoredCredential) -> Result<Self> {
+        let credentials = credential.decrypt()?;
+
+        Ok(Self {
+            id: credential.id,
+            url_prefix: credential.url_prefix,
+            header_names: credentials
+                .headers
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect(),
+            basic_auth: credentials.basic_auth.is_some(),
+            bearer_token: credentials.bearer_token.is_some(),
+            cookie_names: credentials
+                .cookies
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_credentials() {
+        let credentials = AccountCredentials {
+            user_id: 1,
+            credentials: SourceCredentials {
+                headers: vec![("X-Api-Key".to_string(), "key".to_string())],
+                basic_auth: None,
+                bearer_token: Some("token".to_string()),
+                cookies: vec![
+                    ("session".to_string(), "abc".to_string()),
+                    ("theme".to_string(), "dark".to_string()),
+                ],
+            },
+        };
+
+        let request = credentials
+            .apply(reqwest::Client::new().get("https://help.thepagebot.com"))
+            .build()
+            .expect("request");
+        let headers = request.headers();
+
+        assert_eq!(headers["x-api-key"], "key");
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(headers["cookie"], "session=abc; theme=dark");
+        assert!(!format!("{:?}", credentials).contains("token"));
+        assert_eq!(
+            credentials.scope_uri("https://help.thepagebot.com"),
+            "auth:1:https://help.thepagebot.com"
+        );
+    }
+
+    #[test]
+    fn prefix_boundaries() {
+        let credential = |url_prefix: &str| StoredCredential {
+            id: "id".to_string(),
+            url_prefix: url_prefix.to_string(),
+            nonce: vec![],
+            ciphertext: vec![],
+        };
+
+        let host = credential("https://help.example.com");
+        assert!(host.covers("https://help.example.com/x"));
+        assert!(host.covers("https://help.example.com:443/x"));
+        assert!(!host.covers("https://help.example.com.evil.net/x"));
+        assert!(!host.covers("https://help.example.com@evil.net/"));
+        assert!(!host.covers("http://help.example.com/x"));
+        assert!(!host.covers("https://help.example.com:8443/x"));
+
+        let docs = credential("https://help.example.com/docs");
+        assert!(docs.covers("https://help.example.com/docs"));
+        assert!(docs.covers("https://help.example.com/docs/setup"));
+        assert!(!docs.covers("https://help.example.com/docs-private"));
+        assert!(!docs.covers("https://help.example.com/"));
+
+        let slash = credential("https://help.example.com/docs/");
+        assert!(slash.covers("https://help.example.com/docs/setup"));
+        assert!(!slash.covers("https://help.example.com/docs-private"));
+    }
+}
@@ -0,0 +1,112 @@
+use eyre::Result;
+
+use super::source::Source;
+
+const DEFAULT_FEED_TTL: u32 = 60 * 60; // 1 hour
+
+#[derive(Debug, Clone)]
+pub struct FeedItem {
+    pub url: Option<String>,
+    pub content: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Feed {
+    pub ttl: u32, // seconds
+    pub items: Vec<FeedItem>,
+}
+
+impl Feed {
+    /// Parses an RSS or Atom document.
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        let feed = feed_rs::parser::parse(body)
+            .map_err(|e| eyre::eyre!("Failed to parse feed: {:?}", e))?;
+
+        let items = feed
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let body = entry
+                    .content
+                    .and_then(|content| content.body)
+                    .or_else(|| entry.summary.map(|summary| summary.content))
+                    .map(|body| {
+                        // plain text bodies have no elements for the html parser to pick up
+                        let parsed = Source::parse_html(body.clone());
+                        if parsed.trim().is_empty() {
+                            body
+                        } else {
+                            parsed
+                        }
+                    })
+                    .unwrap_or_default();
+
+                let title = entry.title.map(|title| title.content).unwrap_or_default();
+
+                FeedItem {
+                    url: entry.links.into_iter().next().map(|link| link.href),
+                    content: format!("{}\n{}", title, body).trim().to_string(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            ttl: feed.ttl.map(|ttl| ttl * 60).unwrap_or(DEFAULT_FEED_TTL),
+            items,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rss() {
+        let rss = r#"<?xml version="1.0"?>
+            <rss version="2.0">
+                <channel>
+                    <title>Changelog</title>
+                    <ttl>30</ttl>
+                    <item>
+                        <title>Invoices API</title>
+                        <link>https://thepagebot.com/changelog/invoices</link>
+                        <description>You can now create invoices.</description>
+                    </item>
+                </channel>
+            </rss>"#;
+
+        let feed = Feed::parse(rss.as_bytes()).expect("feed");
+        assert_eq!(feed.ttl, 30 * 60);
+        assert_eq!(feed.items.len(), 1);
+        assert_eq!(
+            feed.items[0].url.as_deref(),
+            Some("https://thepagebot.com/changelog/invoices")
+        );
+        assert!(feed.items[0].content.contains("Invoices API"));
+    }
+
+    #[test]
+    fn parse_atom() {
+        let atom = r#"<?xml version="1.0" encoding="utf-8"?>
+            <feed xmlns="http://www.w3.org/2005/Atom">
+                <title>Blog</title>
+                <id>urn:blog</id>
+                <updated>2023-10-01T00:00:00Z</updated>
+                <entry>
+                    <title>Hello</title>
+                    <id>urn:blog:hello</id>
+                    <updated>2023-10-01T00:00:00Z</updated>
+                    <link href="https://thepagebot.com/blog/hello"/>
+                    <summary>First post</summary>
+                </entry>
+            </feed>"#;
+
+        let feed = Feed::parse(atom.as_bytes()).expect("feed");
+        assert_eq!(feed.ttl, DEFAULT_FEED_TTL);
+        assert_eq!(
+            feed.items[0].url.as_deref(),
+            Some("https://thepagebot.com/blog/hello")
+        );
+    }
+}
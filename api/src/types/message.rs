@@ -1,19 +1,31 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use crate::{
+    calibration::{NOT_FOUND_LEXICAL_SCORE, NOT_FOUND_THRESHOLD},
+    chat_provider::Chat,
+    embed_pool::EMBED_POOL,
+    lexical_index,
+    llm_retrieval::{condense_query, context_budget, QUERY_REWRITE},
     notification::{Notification, NotificationType},
+    reranker::{RERANKER, RERANK_TOP_N},
+    retrieval::{self, CandidateChunk, DiversityStats, DIVERSITY, FUSION_WEIGHTS},
     types::source::SourceError,
+    vector_index,
 };
 
 use super::{
+    bundle::Bundle,
+    credential::StoredCredential,
+    history_item::HistoryItem,
+    page_rule::{is_same_page, PageRule},
     perf::Perf,
-    source::{Chunks, Source, SourceInput},
+    pinned_answer::{PinnedAnswer, PinnedAnswerMode},
+    source::{matches_tags, Chunks, LinkedSource, Source, SourceInput, Tags},
     usage::{Usage, UsageItem},
+    user::User,
 };
 use eyre::Result;
 use futures::future::join_all;
-use hnsw_rs::prelude::{DistCosine, Hnsw};
-use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use url_serde::SerdeUrl;
@@ -25,6 +37,16 @@ pub struct Message {
     pub sources: Vec<SourceInput>,
     pub query: String,
     pub page_url: SerdeUrl,
+    // only sources whose tags agree are searched, e.g. {"product": "a"}
+    #[serde(default)]
+    pub tags: Tags,
+    // keeps every candidate chunk for the explain endpoint
+    #[serde(skip)]
+    pub explain: bool,
+    // searches the account's cached sources instead of the message's, without fetching,
+    // saving or linking anything, for explanations and evaluation runs
+    #[serde(skip)]
+    pub read_only: bool,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -36,44 +58,141 @@ pub struct EvaluatedMessage {
     pub query: String,
     pub page_url: String,
     pub perf: Perf,
+    pub pinned_answer: Option<String>,
+    // nothing in the sources is close enough to the query to be worth asking the model
+    pub not_found: bool,
+    pub candidates: Option<Vec<CandidateChunk>>,
 }
 
 const NEIGHBOUR_COUNT: usize = 2;
 impl Message {
-    pub async fn evaluate(self, notification: Arc<Notification>) -> Result<EvaluatedMessage> {
-        let instant_now = std::time::Instant::now();
-
-        let source_inputs = self.sources.into_iter().map(SourceInput::process);
+    /// The message's sources, expanded and fetched when they aren't cached yet,
+    /// along with the account's bundles.
+    async fn retrieve_sources(
+        source_inputs: Vec<SourceInput>,
+        user: &User,
+    ) -> Vec<Result<(Source, bool), SourceError>> {
+        let stored_credentials = StoredCredential::by_user(user.id).unwrap_or_else(|e| {
+            log::error!("Failed to get source credentials: {}", e);
+            vec![]
+        });
+
+        let source_inputs = source_inputs.into_iter().map(|source_input| {
+            source_input
+                .with_account(&stored_credentials, user)
+                .process()
+        });
+        // expanded inputs get credentials matching their own url, not their parent's
         let processed_source_inputs = join_all(source_inputs)
             .await
             .into_iter()
             .filter_map(|source_input| source_input.ok())
-            .flatten();
+            .flatten()
+            .map(|source_input| source_input.with_account(&stored_credentials, user));
 
         let pending_sources = processed_source_inputs.map(Source::new);
-        let sources = join_all(pending_sources).await;
+        let mut sources = join_all(pending_sources).await;
+
+        // uploaded bundles are always part of the account's knowledge
+        match Bundle::sources(user.id) {
+            Ok(bundle_sources) => {
+                sources.extend(bundle_sources.into_iter().map(|source| Ok((source, false))))
+            }
+            Err(e) => log::error!("Failed to get bundle sources: {}", e),
+        }
+        sources
+    }
+
+    pub async fn evaluate(
+        self,
+        user: &User,
+        history: &[HistoryItem],
+        notification: Arc<Notification>,
+    ) -> Result<EvaluatedMessage> {
+        let instant_now = std::time::Instant::now();
+        let chat = Chat::for_user(user)?;
+
+        // follow-ups lean on the conversation, retrieval needs them spelled out
+        let rewritten_query = match history.is_empty() || !*QUERY_REWRITE {
+            true => None,
+            false => condense_query(&chat, history, &self.query)
+                .await
+                .map_err(|e| log::error!("Failed to rewrite query: {}", e))
+                .ok()
+                .filter(|rewritten_query| *rewritten_query != self.query),
+        };
+        let rewrite_time = instant_now.elapsed().as_millis();
+        let retrieval_query = rewritten_query
+            .clone()
+            .unwrap_or_else(|| self.query.clone());
+
+        let query_embedding = Chunks::query(retrieval_query.clone()).await?;
+
+        let pinned_answer = PinnedAnswer::by_user(self.user_id)
+            .map_err(|e| log::error!("Failed to get pinned answers: {}", e))
+            .ok()
+            .and_then(|pinned_answers| {
+                PinnedAnswer::find_match(pinned_answers, &query_embedding, &EMBED_POOL.model_id())
+            });
+
+        if let Some(PinnedAnswer {
+            answer,
+            mode: PinnedAnswerMode::Verbatim,
+            ..
+        }) = pinned_answer
+        {
+            return Ok(EvaluatedMessage {
+                perf: Perf {
+                    rewrite_time: rewrite_time.to_string(),
+                    query: self.query.clone(),
+                    rewritten_query,
+                    ..Default::default()
+                },
+                user_id: self.user_id,
+                token_count: count_tokens(&self.query),
+                query: self.query,
+                page_url: self.page_url.to_string(),
+                pinned_answer: Some(answer),
+                ..Default::default()
+            });
+        }
+
+        let sources = match self.read_only {
+            // linked sources include the account's bundles
+            true => Source::by_user(self.user_id)?
+                .into_iter()
+                .map(|source| Ok((source, false)))
+                .collect(),
+            false => Self::retrieve_sources(self.sources, user).await,
+        };
 
         let retrieval_time = instant_now.elapsed().as_millis();
-        let query_embedding = Chunks::query(self.query.clone()).await?;
 
         let mut cached = true;
-        let ((contents, embeddings), retrieval_count, token_count) = sources.into_iter().fold(
-            ((vec![], vec![]), 0, 0),
-            |((mut contents, mut embeddings), retrieval_count, token_count), source| match source {
-                Ok((mut source, retrieved)) => {
-                    let token_count = token_count + count_tokens(&source.content());
-
-                    contents.append(&mut source.chunks.value.0);
-                    embeddings.append(&mut source.chunks.value.1);
+        let mut source_links = vec![];
+        let ((contents, mut searched_sources), retrieval_count) = sources.into_iter().fold(
+            ((vec![], vec![]), 0),
+            |((mut contents, mut searched_sources), retrieval_count), source| match source {
+                Ok((source, retrieved)) => {
+                    source_links.push(LinkedSource {
+                        uri: source.uri.clone(),
+                        tags: source.tags.clone(),
+                    });
+
+                    // vectors from another model aren't comparable with the query's,
+                    // the source is searched again once reembed_stale has migrated it
+                    if source.chunks.is_current() {
+                        contents.extend(source.chunks.value.0.iter().cloned());
+                        searched_sources.push(source);
+                    }
 
                     if retrieved {
                         cached = false;
                     }
 
                     (
-                        (contents, embeddings),
+                        (contents, searched_sources),
                         retrieval_count + retrieved as u16,
-                        token_count,
                     )
                 }
 
@@ -89,40 +208,176 @@ impl Message {
                             log::error!("Failed to get source: {}", e);
                         }
                     }
-                    ((contents, embeddings), retrieval_count, token_count)
+                    ((contents, searched_sources), retrieval_count)
                 }
             },
         );
         let embedding_time = instant_now.elapsed().as_millis() - retrieval_time;
 
-        let mut token_count = token_count + count_tokens(&self.query);
+        // cached sources are shared between accounts, their tags are the ones this account linked
+        if !self.read_only {
+            let links = Source::link_user(self.user_id, source_links).unwrap_or_else(|e| {
+                log::error!("Failed to link sources to user: {}", e);
+                vec![]
+            });
+            let account_tags = links
+                .iter()
+                .map(|link| (link.uri.as_str(), &link.tags))
+                .collect::<HashMap<_, _>>();
+            for source in searched_sources.iter_mut() {
+                if let Some(tags) = account_tags.get(source.uri.as_str()) {
+                    source.tags = (*tags).clone();
+                }
+            }
+        }
 
-        //5% error margin
-        token_count = token_count + (token_count / 20);
+        // tags and page rules narrow down what's searched, the account keeps all of its sources
+        let page_url = self.page_url.to_string();
+        let page_rule = PageRule::for_page(&user.page_rules, &page_url);
+        let is_scoped = page_rule.map_or(false, |rule| !rule.sources.is_empty());
+        let (contents, searched_sources) = match is_scoped || !self.tags.is_empty() {
+            true => {
+                let searched_sources = searched_sources
+                    .into_iter()
+                    .filter(|source| {
+                        page_rule.map_or(true, |rule| rule.allows(&source.uri))
+                            && matches_tags(&source.tags, &self.tags)
+                    })
+                    .collect::<Vec<_>>();
+                let contents = searched_sources
+                    .iter()
+                    .flat_map(|source| source.chunks.value.0.iter().cloned())
+                    .collect();
+                (contents, searched_sources)
+            }
+            false => (contents, searched_sources),
+        };
+
+        let embeddings_count = contents.len();
+        let source_starts = searched_sources
+            .iter()
+            .scan(0, |start, source| {
+                let source_start = *start;
+                *start += source.chunks.value.0.len();
+                Some(source_start)
+            })
+            .collect::<Vec<_>>();
+        let source_of = |index: usize| source_starts.partition_point(|start| *start <= index) - 1;
+        let source_uri = |index: usize| searched_sources[source_of(index)].uri.clone();
 
-        let embeddings_count = embeddings.len();
+        // exact tokens like skus and error codes are found lexically, paraphrases by the vectors
+        let vector_hits =
+            vector_index::top_similar(self.user_id, &searched_sources, &query_embedding, 50);
+
+        let best_similarity = vector_hits
+            .iter()
+            .map(|(_, similarity)| *similarity)
+            .reduce(f32::max);
+        let lexical_hits = lexical_index::top_matching(&searched_sources, &retrieval_query, 50);
+        let lexical_match = lexical_hits
+            .iter()
+            .any(|(_, score)| *score >= *NOT_FOUND_LEXICAL_SCORE);
+
+        let threshold = user.not_found_threshold.or(*NOT_FOUND_THRESHOLD);
+        if let (Some(threshold), None, false) = (threshold, &pinned_answer, lexical_match) {
+            if best_similarity.unwrap_or_default() < threshold {
+                return Ok(EvaluatedMessage {
+                    perf: Perf {
+                        cached,
+                        retrieval_time: retrieval_time.to_string(),
+                        embedding_time: embedding_time.to_string(),
+                        rewrite_time: rewrite_time.to_string(),
+                        query: self.query.clone(),
+                        rewritten_query,
+                        best_similarity,
+                        ..Default::default()
+                    },
+                    user_id: self.user_id,
+                    retrieval_count,
+                    token_count: count_tokens(&self.query),
+                    query: self.query,
+                    page_url,
+                    not_found: true,
+                    candidates: self.explain.then(|| {
+                        let candidates =
+                            retrieval::fuse(&vector_hits, &lexical_hits, &FUSION_WEIGHTS, 100);
+                        retrieval::explain(&candidates, &[], &[], &contents, source_uri)
+                    }),
+                    ..Default::default()
+                });
+            }
+        }
+        let scores = retrieval::fuse(&vector_hits, &lexical_hits, &FUSION_WEIGHTS, 100);
 
-        let similar_content_index = top_similar_indexes(embeddings, &query_embedding, 50);
-        let similar_content_index_with_neighbours_index = similar_content_index
+        // the visitor's page likely holds the answer
+        let boosted_sources = searched_sources
             .iter()
-            // get all neighbours of indexes (left and right, including self)
-            .flat_map(|&index| {
-                let left = index.saturating_sub(NEIGHBOUR_COUNT);
-                let right = index + NEIGHBOUR_COUNT;
-                left..=right.min(embeddings_count - 1)
+            .map(|source| {
+                is_same_page(&source.uri, &page_url)
+                    || page_rule.map_or(false, |rule| rule.boosts(&source.uri))
             })
-            .unique();
-
-        let merged_similar_content = similar_content_index_with_neighbours_index
-            .map(|i| &contents[i])
-            .fold(
-                String::with_capacity(contents.len() * contents[0].len()),
-                |mut acc, content| {
-                    acc.push_str(content);
-                    acc.push('\n');
-                    acc
-                },
-            );
+            .collect::<Vec<_>>();
+        let scores = retrieval::boost(
+            scores,
+            |index| boosted_sources[source_of(index)],
+            &FUSION_WEIGHTS,
+            100,
+        );
+        let candidates = self.explain.then(|| scores.clone());
+        let candidate_sources =
+            retrieval::source_count(scores.iter().map(|score| score.index), source_of);
+
+        // long repetitive pages would otherwise fill the context on their own
+        let embeddings = searched_sources
+            .iter()
+            .flat_map(|source| source.chunks.value.1.iter())
+            .collect::<Vec<_>>();
+        let scores = retrieval::diversify(scores, &embeddings, source_of, &DIVERSITY, 50);
+        let selected_sources =
+            retrieval::source_count(scores.iter().map(|score| score.index), source_of);
+
+        let rerank_instant = std::time::Instant::now();
+        let (scores, contents) = match RERANKER.as_ref() {
+            Some(reranker) => {
+                let query = retrieval_query.clone();
+                tokio::task::spawn_blocking(move || {
+                    let scores = retrieval::rerank(
+                        reranker.as_ref(),
+                        &query,
+                        &contents,
+                        scores,
+                        *RERANK_TOP_N,
+                    );
+                    (scores, contents)
+                })
+                .await?
+            }
+            None => (scores, contents),
+        };
+        let rerank_time = rerank_instant.elapsed().as_millis();
+
+        // pinned answers in context mode always lead the retrieved information
+        let pinned_context = pinned_answer
+            .map(|pinned_answer| format!("{}\n{}\n", pinned_answer.question, pinned_answer.answer))
+            .unwrap_or_default();
+
+        let passages =
+            retrieval::passages(&scores, &source_starts, embeddings_count, NEIGHBOUR_COUNT);
+        let token_budget = context_budget(&chat, history, &self.query)
+            .saturating_sub(count_tokens(&pinned_context));
+        let (context, context_chunks) = retrieval::assemble(&contents, &passages, token_budget);
+        let merged_similar_content = pinned_context + &context;
+        let diversity = DiversityStats {
+            candidate_sources,
+            selected_sources,
+            context_sources: retrieval::source_count(context_chunks.iter().copied(), source_of),
+            context_chunks: context_chunks.len(),
+        };
+
+        let token_count = count_tokens(&merged_similar_content) + count_tokens(&self.query);
+        let candidates = candidates.map(|candidates| {
+            retrieval::explain(&candidates, &scores, &context_chunks, &contents, source_uri)
+        });
 
         let search_time = instant_now.elapsed().as_millis() - embedding_time - retrieval_time;
 
@@ -133,7 +388,14 @@ impl Message {
                 retrieval_time: retrieval_time.to_string(),
                 embedding_time: embedding_time.to_string(),
                 search_time: search_time.to_string(),
+                rerank_time: rerank_time.to_string(),
+                rewrite_time: rewrite_time.to_string(),
+                query: self.query.clone(),
+                rewritten_query,
+                best_similarity,
+                diversity,
                 context: merged_similar_content.clone(),
+                scores,
                 ..Default::default()
             },
             user_id: self.user_id,
@@ -141,7 +403,10 @@ impl Message {
             retrieval_count,
             token_count,
             query: self.query,
-            page_url: self.page_url.to_string(),
+            page_url,
+            pinned_answer: None,
+            not_found: false,
+            candidates,
         })
     }
 }
@@ -236,19 +501,16 @@ pub fn count_tokens(text: &str) -> usize {
     tokens
 }
 
-pub fn top_similar_indexes(embeddings: Vec<Vec<f32>>, query: &[f32], kn: usize) -> Vec<usize> {
-    // let instant = std::time::Instant::now();
-    let hnsw = Hnsw::new(90, embeddings.len(), 16, 50, DistCosine);
-    let embedding_w_index: Vec<(&Vec<f32>, usize)> = embeddings
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (dot, norm_a, norm_b) = a
         .iter()
-        .enumerate()
-        .map(|(i, embedding)| (embedding, i))
-        .collect();
-    hnsw.parallel_insert(&embedding_w_index);
-    let neighbours = hnsw.search(query, kn, 60);
-    // log::info!("hnsw search took {:?}", instant.elapsed());
-    neighbours
-        .into_iter()
-        .map(|n| n.d_id)
-        .collect::<Vec<usize>>()
+        .zip(b)
+        .fold((0.0, 0.0, 0.0), |(dot, norm_a, norm_b), (a, b)| {
+            (dot + a * b, norm_a + a * a, norm_b + b * b)
+        });
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
 }
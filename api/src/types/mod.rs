@@ -1,6 +1,12 @@
+pub mod bundle;
+pub mod credential;
+pub mod feed;
 pub mod history_item;
 pub mod message;
+pub mod openapi;
+pub mod page_rule;
 pub mod perf;
+pub mod pinned_answer;
 pub mod query;
 pub mod source;
 pub mod usage;
@@ -0,0 +1,201 @@
+use eyre::Result;
+use serde_json::Value;
+
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+#[derive(Debug, Clone)]
+pub struct OpenApiOperation {
+    pub id: String,
+    pub content: String,
+}
+
+/// Splits an OpenAPI (or Swagger) document, JSON or YAML, into one self-contained text per operation.
+pub fn parse_operations(body: &str) -> Result<Vec<OpenApiOperation>> {
+    let spec: Value = serde_json::from_str(body)
+        .or_else(|_| serde_yaml::from_str(body))
+        .map_err(|e| eyre::eyre!("Failed to parse openapi spec: {:?}", e))?;
+
+    let paths = spec
+        .get("paths")
+        .and_then(Value::as_object)
+        .ok_or_else(|| eyre::eyre!("OpenAPI spec has no paths"))?;
+
+    let operations = paths
+        .iter()
+        .flat_map(|(path, path_item)| {
+            let path_item = resolve(&spec, path_item);
+            let path_parameters = path_item
+                .get("parameters")
+                .and_then(Value::as_array)
+                .cloned()
+                .unwrap_or_default();
+
+            METHODS
+                .iter()
+                .filter_map(|method| {
+                    path_item.get(*method).map(|operation| {
+                        render_operation(&spec, method, path, operation, &path_parameters)
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    Ok(operations)
+}
+
+fn render_operation(
+    spec: &Value,
+    method: &str,
+    path: &str,
+    operation: &Value,
+    path_parameters: &[Value],
+) -> OpenApiOperation {
+    let mut lines = vec![format!("{} {}", method.to_uppercase(), path)];
+
+    for field in ["summary", "description"] {
+        if let Some(text) = operation.get(field).and_then(Value::as_str) {
+            lines.push(text.trim().to_string());
+        }
+    }
+
+    let parameters = path_parameters
+        .iter()
+        .chain(
+            operation
+                .get("parameters")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten(),
+        )
+        .map(|parameter| resolve(spec, parameter))
+        .collect::<Vec<_>>();
+
+    if !parameters.is_empty() {
+        lines.push("Parameters:".to_string());
+        for parameter in parameters {
+            lines.push(format!(
+                "- {} ({}, {}): {}",
+                text(parameter, "name"),
+                text(parameter, "in"),
+                if parameter.get("required").and_then(Value::as_bool) == Some(true) {
+                    "required"
+                } else {
+                    "optional"
+                },
+                text(parameter, "description"),
+            ));
+        }
+    }
+
+    if let Some(request_body) = operation.get("requestBody").map(|body| resolve(spec, body)) {
+        let content_types = request_body
+            .get("content")
+            .and_then(Value::as_object)
+            .map(|content| content.keys().cloned().collect::<Vec<_>>().join(", "))
+            .unwrap_or_default();
+        lines.push(format!(
+            "Request body ({}): {}",
+            content_types,
+            text(request_body, "description")
+        ));
+    }
+
+    if let Some(responses) = operation.get("responses").and_then(Value::as_object) {
+        lines.push("Responses:".to_string());
+        for (status, response) in responses {
+            lines.push(format!(
+                "- {}: {}",
+                status,
+                text(resolve(spec, response), "description")
+            ));
+        }
+    }
+
+    OpenApiOperation {
+        id: operation
+            .get("operationId")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{}-{}", method, path)),
+        content: lines.join("\n"),
+    }
+}
+
+fn text<'a>(value: &'a Value, field: &str) -> &'a str {
+    value.get(field).and_then(Value::as_str).unwrap_or_default()
+}
+
+// follows local "$ref": "#/components/..." pointers, leaving remote refs as they are
+fn resolve<'a>(spec: &'a Value, value: &'a Value) -> &'a Value {
+    match value.get("$ref").and_then(Value::as_str) {
+        Some(reference) if reference.starts_with('#') => spec
+            .pointer(&reference[1..])
+            .map(|resolved| resolve(spec, resolved))
+            .unwrap_or(value),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operations_from_json() {
+        let spec = r##"{
+            "openapi": "3.0.0",
+            "paths": {
+                "/invoices": {
+                    "post": {
+                        "operationId": "createInvoice",
+                        "summary": "Create an invoice",
+                        "parameters": [{ "$ref": "#/components/parameters/Idempotency" }],
+                        "requestBody": {
+                            "description": "The invoice to create",
+                            "content": { "application/json": {} }
+                        },
+                        "responses": { "201": { "description": "Invoice created" } }
+                    },
+                    "get": { "summary": "List invoices" }
+                }
+            },
+            "components": {
+                "parameters": {
+                    "Idempotency": {
+                        "name": "Idempotency-Key",
+                        "in": "header",
+                        "required": true,
+                        "description": "Unique request key"
+                    }
+                }
+            }
+        }"##;
+
+        let operations = parse_operations(spec).expect("operations");
+        assert_eq!(operations.len(), 2);
+
+        let create = operations
+            .iter()
+            .find(|operation| operation.id == "createInvoice")
+            .expect("createInvoice");
+        assert!(create.content.starts_with("POST /invoices"));
+        assert!(create
+            .content
+            .contains("- Idempotency-Key (header, required): Unique request key"));
+        assert!(create.content.contains("- 201: Invoice created"));
+
+        assert!(operations
+            .iter()
+            .any(|operation| operation.id == "get-/invoices"));
+    }
+
+    #[test]
+    fn operations_from_yaml() {
+        let spec = "openapi: 3.0.0\npaths:\n  /status:\n    get:\n      summary: Service status\n";
+        let operations = parse_operations(spec).expect("operations");
+        assert_eq!(operations[0].content, "GET /status\nService status");
+    }
+}
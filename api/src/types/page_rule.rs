@@ -0,0 +1,127 @@
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+/// Scopes retrieval on some of an account's pages, e.g. only the `/docs/v2/*` sources on
+/// `/docs/v2/*` pages. Patterns starting with `/` match the url's path, others the whole url,
+/// `*` matches anything.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PageRule {
+    pub pages: String,
+    // only these sources are searched, all of them when empty
+    #[serde(default)]
+    pub sources: Vec<String>,
+    // sources favoured like the page itself
+    #[serde(default)]
+    pub boost: Vec<String>,
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // no wildcard
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(position) => rest = &rest[position + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+fn url_matches(pattern: &str, url: &str) -> bool {
+    if pattern.starts_with('/') {
+        Url::parse(url)
+            .map(|url| glob_match(pattern, url.path()))
+            .unwrap_or(false)
+    } else {
+        glob_match(pattern, url)
+    }
+}
+
+/// Whether the source was made from the page, ignoring the query, fragment and trailing slash.
+pub fn is_same_page(uri: &str, page_url: &str) -> bool {
+    match (Url::parse(uri), Url::parse(page_url)) {
+        (Ok(uri), Ok(page_url)) => {
+            uri.host_str() == page_url.host_str()
+                && uri.path().trim_end_matches('/') == page_url.path().trim_end_matches('/')
+        }
+        _ => false,
+    }
+}
+
+impl PageRule {
+    /// The first rule whose pages match, rules are checked in order.
+    pub fn for_page<'a>(rules: &'a [PageRule], page_url: &str) -> Option<&'a PageRule> {
+        rules.iter().find(|rule| url_matches(&rule.pages, page_url))
+    }
+
+    pub fn allows(&self, uri: &str) -> bool {
+        self.sources.is_empty() || self.sources.iter().any(|pattern| url_matches(pattern, uri))
+    }
+
+    pub fn boosts(&self, uri: &str) -> bool {
+        self.boost.iter().any(|pattern| url_matches(pattern, uri))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(glob_match("/docs/v2/*", "/docs/v2/setup"));
+        assert!(glob_match("/docs/*/setup", "/docs/v2/setup"));
+        assert!(!glob_match("/docs/v2/*", "/docs/v1/setup"));
+        assert!(glob_match("/pricing", "/pricing"));
+        assert!(!glob_match("/pricing", "/pricing/enterprise"));
+        assert!(url_matches(
+            "https://thepagebot.com/blog/*",
+            "https://thepagebot.com/blog/launch"
+        ));
+    }
+
+    #[test]
+    fn page_scoped_sources() {
+        let rules = vec![
+            PageRule {
+                pages: "/docs/v2/*".to_string(),
+                sources: vec!["/docs/v2/*".to_string()],
+                boost: vec!["/changelog".to_string()],
+            },
+            PageRule {
+                pages: "*".to_string(),
+                sources: vec![],
+                boost: vec![],
+            },
+        ];
+
+        let rule =
+            PageRule::for_page(&rules, "https://thepagebot.com/docs/v2/setup?tab=1").expect("rule");
+        assert!(rule.allows("https://thepagebot.com/docs/v2/install"));
+        assert!(!rule.allows("https://thepagebot.com/docs/v1/install"));
+        assert!(rule.boosts("https://thepagebot.com/changelog"));
+
+        let rule = PageRule::for_page(&rules, "https://thepagebot.com/pricing").expect("rule");
+        assert!(rule.allows("https://thepagebot.com/docs/v1/install"));
+
+        assert!(is_same_page(
+            "https://thepagebot.com/pricing/",
+            "https://thepagebot.com/pricing#plans"
+        ));
+        assert!(!is_same_page(
+            "https://thepagebot.com/pricing",
+            "https://thepagebot.com/"
+        ));
+    }
+}
@@ -1,11 +1,22 @@
+use crate::retrieval::{ChunkScore, DiversityStats};
+
 #[derive(Debug, Clone, serde::Serialize, Default)]
 pub struct Perf {
     pub retrieval_time: String,
     pub context: String,
     pub embedding_time: String,
     pub search_time: String,
+    pub rerank_time: String,
+    pub rewrite_time: String,
     pub total_time: String,
     pub first_chunk_time: String,
     pub token_count: usize,
     pub cached: bool,
+    pub scores: Vec<ChunkScore>,
+    // what the customer asked and, for follow-ups, what was searched for instead
+    pub query: String,
+    pub rewritten_query: Option<String>,
+    // similarity of the closest chunk, compared against the not found threshold
+    pub best_similarity: Option<f32>,
+    pub diversity: DiversityStats,
 }
@@ -0,0 +1,244 @@
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::DB,
+    embed_pool::{Embedding, EMBED_POOL},
+};
+
+use super::{
+    message::cosine_similarity,
+    source::{legacy_dimension, legacy_model},
+};
+
+/// Minimum cosine similarity between a query and a pinned question for the pin to apply.
+pub const PINNED_ANSWER_THRESHOLD: f32 = 0.85;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PinnedAnswer {
+    pub id: String,
+    pub question: String,
+    pub answer: String,
+    pub mode: PinnedAnswerMode,
+    pub embedding: Embedding,
+    #[serde(default = "legacy_model")]
+    pub model: String,
+    #[serde(default = "legacy_dimension")]
+    pub dimension: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PinnedAnswerMode {
+    // stream the answer as is, the llm is never called
+    #[default]
+    Verbatim,
+    // put the answer at the top of the retrieved context
+    Context,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PinnedAnswerInput {
+    pub id: Option<String>,
+    pub question: String,
+    pub answer: String,
+    #[serde(default)]
+    pub mode: PinnedAnswerMode,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PinnedAnswerOutput {
+    pub id: String,
+    pub question: String,
+    pub answer: String,
+    pub mode: PinnedAnswerMode,
+}
+
+impl PinnedAnswer {
+    pub async fn new(input: PinnedAnswerInput) -> Result<Self> {
+        if input.question.trim().is_empty() || input.answer.trim().is_empty() {
+            return Err(eyre::eyre!("Question and answer are required"));
+        }
+
+        let embedding = EMBED_POOL
+            .encode(vec![input.question.clone()])
+            .await?
+            .pop()
+            .ok_or_else(|| eyre::eyre!("Failed to receive embeddings"))?;
+
+        Ok(Self {
+            id: input.id.unwrap_or_else(|| nanoid::nanoid!()),
+            question: input.question,
+            answer: input.answer,
+            mode: input.mode,
+            embedding,
+            model: EMBED_POOL.model_id(),
+            dimension: EMBED_POOL.dimension(),
+        })
+    }
+
+    pub fn by_user(user_id: u64) -> Result<Vec<PinnedAnswer>> {
+        DB.pinned_answers(user_id)
+    }
+
+    /// Inserts the entry, replacing any existing entry with the same id.
+    pub fn save(self, user_id: u64) -> Result<Self> {
+        DB.pinned_answers_update(user_id, |pinned_answers| {
+            pinned_answers.retain(|pinned_answer| pinned_answer.id != self.id);
+            pinned_answers.push(self.clone());
+            true
+        })?;
+        Ok(self)
+    }
+
+    pub fn delete(user_id: u64, id: &str) -> Result<bool> {
+        let mut deleted = false;
+        DB.pinned_answers_update(user_id, |pinned_answers| {
+            let count = pinned_answers.len();
+            pinned_answers.retain(|pinned_answer| pinned_answer.id != id);
+            deleted = pinned_answers.len() != count;
+            deleted
+        })?;
+        Ok(deleted)
+    }
+
+    /// Re-embeds the questions of every pinned answer produced by another model.
+    /// Failures are logged and skipped, the rest are still migrated.
+    pub async fn reembed_stale() -> Result<usize> {
+        let model = EMBED_POOL.model_id();
+        let mut migrated = 0;
+        for user_id in DB.pinned_answer_user_ids()? {
+            let pinned_answers = match DB.pinned_answers(user_id) {
+                Ok(pinned_answers) => pinned_answers,
+                Err(e) => {
+                    log::error!("Failed to get pinned answers of {}: {}", user_id, e);
+                    continue;
+                }
+            };
+
+            let mut embedded = vec![];
+            for pinned_answer in pinned_answers {
+                if pinned_answer.model == model {
+                    continue;
+                }
+
+                let embedding = EMBED_POOL
+                    .encode(vec![pinned_answer.question.clone()])
+                    .await
+                    .and_then(|mut embeddings| {
+                        embeddings
+                            .pop()
+                            .ok_or_else(|| eyre::eyre!("Failed to receive embeddings"))
+                    });
+                match embedding {
+                    Ok(embedding) => embedded.push((pinned_answer, embedding)),
+                    Err(e) => log::error!(
+                        "Failed to re-embed pinned answer {} of {}: {}",
+                        pinned_answer.id,
+                        user_id,
+                        e
+                    ),
+                }
+            }
+            if embedded.is_empty() {
+                continue;
+            }
+
+            // answers edited while embedding were embedded by their save and are left as is
+            let mut updated = 0;
+            let saved = DB.pinned_answers_update(user_id, |pinned_answers| {
+                for pinned_answer in pinned_answers.iter_mut() {
+                    let embedding = embedded.iter().find(|(stale, _)| {
+                        stale.id == pinned_answer.id
+                            && stale.question == pinned_answer.question
+                            && stale.model == pinned_answer.model
+                    });
+                    if let Some((_, embedding)) = embedding {
+                        pinned_answer.embedding = embedding.clone();
+                        pinned_answer.model = model.clone();
+                        pinned_answer.dimension = EMBED_POOL.dimension();
+                        updated += 1;
+                    }
+                }
+                updated > 0
+            });
+            match saved {
+                Ok(_) => migrated += updated,
+                Err(e) => log::error!("Failed to save pinned answers of {}: {}", user_id, e),
+            }
+        }
+        Ok(migrated)
+    }
+
+    /// Returns the closest pinned answer to the query if it clears the threshold,
+    /// ignoring answers embedded by a model other than the query's.
+    pub fn find_match(
+        pinned_answers: Vec<PinnedAnswer>,
+        query_embedding: &[f32],
+        model: &str,
+    ) -> Option<PinnedAnswer> {
+        pinned_answers
+            .into_iter()
+            .filter(|pinned_answer| {
+                pinned_answer.model == model && pinned_answer.dimension == query_embedding.len()
+            })
+            .map(|pinned_answer| {
+                let similarity = cosine_similarity(&pinned_answer.embedding, query_embedding);
+                (pinned_answer, similarity)
+            })
+            .filter(|(_, similarity)| *similarity >= PINNED_ANSWER_THRESHOLD)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(pinned_answer, _)| pinned_answer)
+    }
+}
+
+impl From<PinnedAnswer> for PinnedAnswerOutput {
+    fn from(pinned_answer: PinnedAnswer) -> Self {
+        Self {
+            id: pinned_answer.id,
+            question: pinned_answer.question,
+            answer: pinned_answer.answer,
+            mode: pinned_answer.mode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinned_answer(id: &str, embedding: Embedding) -> PinnedAnswer {
+        PinnedAnswer {
+            id: id.to_string(),
+            question: "What's the refund policy ?".to_string(),
+            answer: "Refunds are issued within 30 days.".to_string(),
+            mode: PinnedAnswerMode::Verbatim,
+            dimension: embedding.len(),
+            embedding,
+            model: "fake/2".to_string(),
+        }
+    }
+
+    #[test]
+    fn find_match_above_threshold() {
+        let pinned_answers = vec![
+            pinned_answer("far", vec![0.0, 1.0]),
+            pinned_answer("close", vec![1.0, 0.1]),
+        ];
+        let matched =
+            PinnedAnswer::find_match(pinned_answers, &[1.0, 0.0], "fake/2").expect("match");
+        assert_eq!(matched.id, "close");
+    }
+
+    #[test]
+    fn find_match_below_threshold() {
+        let pinned_answers = vec![pinned_answer("far", vec![0.0, 1.0])];
+        assert!(PinnedAnswer::find_match(pinned_answers, &[1.0, 0.0], "fake/2").is_none());
+    }
+
+    #[test]
+    fn find_match_other_model() {
+        let pinned_answers = vec![pinned_answer("close", vec![1.0, 0.1])];
+        assert!(PinnedAnswer::find_match(pinned_answers, &[1.0, 0.0], "fake/3").is_none());
+    }
+}
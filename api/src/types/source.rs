@@ -1,8 +1,13 @@
-use std::hash::{Hash, Hasher};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
 
 use crate::{
     db::DB,
     embed_pool::{Embedding, EMBED_POOL},
+    renderer::{Renderer, RendererConfig},
 };
 use axum::http::HeaderValue;
 use docx_rust::document::{ParagraphContent, RunContent};
@@ -14,6 +19,13 @@ use serde_aux::prelude::deserialize_number_from_string;
 use sitemap::reader::{SiteMapEntity, SiteMapReader};
 use unicode_segmentation::UnicodeSegmentation;
 use url_serde::SerdeUrl;
+
+use super::{
+    credential::{AccountCredentials, StoredCredential},
+    feed::Feed,
+    openapi::parse_operations,
+    user::User,
+};
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SourceInput {
     content: Option<String>,
@@ -23,23 +35,290 @@ pub struct SourceInput {
         deserialize_with = "deserialize_number_from_string"
     )]
     expires: u32,
+    // detected from the url when not given
+    #[serde(default)]
+    kind: Option<SourceKind>,
+    #[serde(default)]
+    feed_mode: FeedMode,
+    // carried over to the sources a sitemap, feed or spec expands into
+    #[serde(default)]
+    tags: Tags,
+    #[serde(skip)]
+    chunking: Chunking,
+    #[serde(skip)]
+    credentials: Option<AccountCredentials>,
+    #[serde(skip)]
+    renderer: RendererConfig,
+}
+
+/// Labels like product, version, language or audience that messages can filter sources by.
+pub type Tags = BTreeMap<String, String>;
+
+/// Keeps the sources that don't contradict any of its tags, untagged ones stay shared.
+pub fn matches_tags(tags: &Tags, filter: &Tags) -> bool {
+    filter
+        .iter()
+        .all(|(key, value)| tags.get(key).map_or(true, |tag| tag == value))
+}
+
+/// Adds the links the account doesn't have yet and updates the tags of the ones it has,
+/// returning whether anything changed.
+fn merge_links(owned: &mut Vec<LinkedSource>, links: &[LinkedSource]) -> bool {
+    let mut changed = false;
+    for link in links {
+        match owned.iter_mut().find(|owned| owned.uri == link.uri) {
+            Some(owned) => {
+                if !link.tags.is_empty() && owned.tags != link.tags {
+                    owned.tags = link.tags.clone();
+                    changed = true;
+                }
+            }
+            None => {
+                owned.push(link.clone());
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// A source an account uses along with the tags it gave it. Cached sources are shared
+/// between accounts, so the tags are kept here rather than on the source.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(from = "StoredLink")]
+pub struct LinkedSource {
+    pub uri: String,
+    pub tags: Tags,
+}
+
+// links were stored as plain uris before they carried tags
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredLink {
+    Uri(String),
+    Tagged {
+        uri: String,
+        #[serde(default)]
+        tags: Tags,
+    },
+}
+
+impl From<StoredLink> for LinkedSource {
+    fn from(link: StoredLink) -> Self {
+        match link {
+            StoredLink::Uri(uri) => Self {
+                uri,
+                tags: Tags::default(),
+            },
+            StoredLink::Tagged { uri, tags } => Self { uri, tags },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    Page,
+    Sitemap,
+    Feed,
+    OpenApi,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedMode {
+    // every item link becomes a page source
+    #[default]
+    Expand,
+    // the item content in the feed is indexed directly
+    Content,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Chunking {
+    #[default]
+    Sentences,
+    // the whole content is embedded as a single chunk
+    Whole,
+}
+
+impl Default for SourceInput {
+    fn default() -> Self {
+        Self {
+            content: None,
+            url: None,
+            expires: default_expires(),
+            kind: None,
+            feed_mode: FeedMode::default(),
+            tags: Tags::default(),
+            chunking: Chunking::default(),
+            credentials: None,
+            renderer: RendererConfig::default(),
+        }
+    }
+}
+
+lazy_static! {
+    // feed and openapi expansions keyed by url, with the timestamp they expire at
+    static ref EXPANSION_CACHE: Mutex<HashMap<String, (u32, Vec<SourceInput>)>> =
+        Mutex::new(HashMap::new());
 }
 
 impl SourceInput {
+    fn kind(&self) -> SourceKind {
+        if let Some(kind) = self.kind {
+            return kind;
+        }
+
+        let url = match self.url.as_ref() {
+            Some(url) => url.to_string().to_lowercase(),
+            None => return SourceKind::Page,
+        };
+
+        if url.contains("sitemap") && url.contains("xml") {
+            SourceKind::Sitemap
+        } else if (url.contains("openapi") || url.contains("swagger"))
+            && (url.ends_with(".json") || url.ends_with(".yaml") || url.ends_with(".yml"))
+        {
+            SourceKind::OpenApi
+        } else if url.ends_with(".rss")
+            || url.ends_with(".atom")
+            || url.ends_with("/feed")
+            || url.ends_with("/feed/")
+            || url.ends_with("/rss")
+            || url.ends_with("feed.xml")
+            || url.ends_with("rss.xml")
+            || url.ends_with("atom.xml")
+        {
+            SourceKind::Feed
+        } else {
+            SourceKind::Page
+        }
+    }
+
     fn is_sitemap(&self) -> bool {
-        self.url
+        self.kind() == SourceKind::Sitemap
+    }
+
+    /// Attaches the account's renderer and its stored credentials matching the url, if any.
+    pub fn with_account(mut self, stored: &[StoredCredential], user: &User) -> Self {
+        self.renderer = user.renderer.clone();
+        if let Some(url) = self.url.as_ref() {
+            self.credentials = AccountCredentials::matching(stored, user.id, url.as_str())
+                .map_err(|e| log::error!("Failed to decrypt source credentials: {}", e))
+                .ok()
+                .flatten();
+        }
+        self
+    }
+
+    fn cache_key(&self, url: &str) -> String {
+        match &self.credentials {
+            Some(credentials) => credentials.scope_uri(url),
+            None => url.to_string(),
+        }
+    }
+
+    fn cached_expansion(key: &str) -> Option<Vec<Self>> {
+        let cache = EXPANSION_CACHE.lock().unwrap();
+        cache
+            .get(key)
+            .filter(|(expires_at, _)| *expires_at > chrono::Utc::now().timestamp() as u32)
+            .map(|(_, source_inputs)| source_inputs.clone())
+    }
+
+    fn cache_expansion(key: String, ttl: u32, source_inputs: Vec<Self>) -> Vec<Self> {
+        let expires_at = chrono::Utc::now().timestamp() as u32 + ttl;
+        let mut cache = EXPANSION_CACHE.lock().unwrap();
+        cache.insert(key, (expires_at, source_inputs.clone()));
+        source_inputs
+    }
+
+    async fn fetch_feed(self) -> Result<Vec<Self>> {
+        let url = self
+            .url
             .as_ref()
-            .map(|url| {
-                let url = url.to_string();
-                url.contains("sitemap") && url.contains("xml")
+            .ok_or_else(|| eyre::eyre!("A feed source needs a url"))?
+            .to_string();
+        let key = format!("{:?}:{}", self.feed_mode, self.cache_key(&url));
+        if let Some(source_inputs) = Self::cached_expansion(&key) {
+            return Ok(source_inputs);
+        }
+
+        let body = Source::get(&url, self.credentials.as_ref())
+            .await?
+            .bytes()
+            .await?;
+        let feed = Feed::parse(&body)?;
+
+        let source_inputs = feed
+            .items
+            .into_iter()
+            .filter_map(|item| {
+                let item_url: Option<SerdeUrl> = item
+                    .url
+                    .and_then(|url| serde_json::from_str(format!("\"{}\"", url).as_str()).ok());
+
+                match self.feed_mode {
+                    FeedMode::Expand => item_url.map(|item_url| Self {
+                        url: Some(item_url),
+                        expires: self.expires,
+                        ..Default::default()
+                    }),
+                    FeedMode::Content if !item.content.is_empty() => Some(Self {
+                        content: Some(item.content),
+                        url: item_url,
+                        expires: feed.ttl,
+                        ..Default::default()
+                    }),
+                    FeedMode::Content => None,
+                }
             })
-            .unwrap_or(false)
+            .collect::<Vec<_>>();
+
+        // the feed is only fetched again once its own ttl has passed
+        Ok(Self::cache_expansion(key, feed.ttl, source_inputs))
+    }
+
+    async fn fetch_openapi_operations(self) -> Result<Vec<Self>> {
+        let url = self
+            .url
+            .clone()
+            .ok_or_else(|| eyre::eyre!("An openapi source needs a url"))?;
+        let key = self.cache_key(url.as_str());
+        if let Some(source_inputs) = Self::cached_expansion(&key) {
+            return Ok(source_inputs);
+        }
+
+        let body = Source::get(url.as_str(), self.credentials.as_ref())
+            .await?
+            .text()
+            .await?;
+
+        let source_inputs = parse_operations(&body)?
+            .into_iter()
+            .map(|operation| {
+                let mut operation_url = url.clone();
+                operation_url.set_fragment(Some(&operation.id));
+                Self {
+                    content: Some(operation.content),
+                    url: Some(operation_url),
+                    expires: self.expires,
+                    chunking: Chunking::Whole,
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Self::cache_expansion(key, self.expires, source_inputs))
     }
 
     async fn fetch_xml_urls(self) -> Result<Vec<Self>> {
-        let client = Source::create_get_client();
-        let url = self.url.as_ref().unwrap();
-        let resp = client.get(url.to_string()).send().await?;
+        let url = self
+            .url
+            .as_ref()
+            .ok_or_else(|| eyre::eyre!("A sitemap source needs a url"))?;
+        let resp = Source::get(url.as_str(), self.credentials.as_ref()).await?;
         let body = resp.text().await?;
 
         let parser = SiteMapReader::new(body.as_bytes());
@@ -51,9 +330,9 @@ impl SourceInput {
                     let serde_url: Result<SerdeUrl, _> =
                         serde_json::from_str(format!("\"{}\"", url).as_str());
                     Self {
-                        content: None,
                         url: serde_url.ok(),
                         expires: self.expires,
+                        ..Default::default()
                     }
                 }),
                 _ => None,
@@ -64,12 +343,24 @@ impl SourceInput {
     }
 
     pub async fn process(self) -> Result<Vec<Self>, SourceError> {
-        if self.is_sitemap() {
+        let tags = self.tags.clone();
+        let source_inputs = match self.kind() {
             // @todo: cache sitemap by its url
-            self.fetch_xml_urls().await.map_err(SourceError::Default)
-        } else {
-            Ok(vec![self])
+            SourceKind::Sitemap => self.fetch_xml_urls().await,
+            SourceKind::Feed => self.fetch_feed().await,
+            SourceKind::OpenApi => self.fetch_openapi_operations().await,
+            SourceKind::Page => return Ok(vec![self]),
         }
+        .map_err(SourceError::Default)?;
+
+        // expansions are cached for every account, the tags are this input's
+        Ok(source_inputs
+            .into_iter()
+            .map(|source_input| Self {
+                tags: tags.clone(),
+                ..source_input
+            })
+            .collect())
     }
 }
 
@@ -84,12 +375,52 @@ pub struct Source {
     pub expires: u32,
     pub created_at: u32,
     pub chunks: Chunks,
+    #[serde(default)]
+    pub fetch_status: FetchStatus,
+    // the tags of the account's link, the cached record is shared and never stores them
+    #[serde(skip)]
+    pub tags: Tags,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchStatus {
+    #[default]
+    Ok,
+    Empty,
+    Failed(String),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SourceOutput {
+    pub uri: String,
+    pub created_at: u32,
+    pub expires_at: u32,
+    pub chunk_count: usize,
+    pub byte_size: usize,
+    pub fetch_status: FetchStatus,
+    pub model: String,
+    pub tags: Tags,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Chunks {
     pub url: String,
     pub value: (Vec<String>, Vec<Embedding>), // (sentence, embedding)
+    // the embedder that produced the vectors, only vectors of the current one are compared
+    #[serde(default = "legacy_model")]
+    pub model: String,
+    #[serde(default = "legacy_dimension")]
+    pub dimension: usize,
+}
+
+// chunks saved before models were recorded all came from this one
+pub(crate) fn legacy_model() -> String {
+    "rust-bert/all-mini-lm-l6-v2".to_string()
+}
+
+pub(crate) fn legacy_dimension() -> usize {
+    384
 }
 
 #[derive(Debug)]
@@ -107,6 +438,7 @@ pub enum SourceError {
     Default(Report),
 }
 const MIN_CONTENT_LENGTH: usize = 100;
+const MAX_REDIRECTS: usize = 10;
 
 impl Source {
     pub fn by_url(url: &str) -> Result<Option<Source>> {
@@ -128,7 +460,135 @@ impl Source {
         self.chunks.value.0.join(" ")
     }
 
-    fn parse_html(html: String) -> String {
+    /// The account's sources, tagged as the account tagged them.
+    pub fn by_user(user_id: u64) -> Result<Vec<Source>> {
+        let sources = DB
+            .user_sources(user_id)?
+            .into_iter()
+            .filter_map(|link| {
+                DB.source_cache(&link.uri)
+                    .ok()
+                    .flatten()
+                    .map(|source| Source {
+                        tags: link.tags,
+                        ..source
+                    })
+            })
+            .collect();
+        Ok(sources)
+    }
+
+    /// One of the account's sources, tagged as the account tagged it. None if the account
+    /// doesn't link the uri.
+    pub fn by_user_uri(user_id: u64, uri: &str) -> Result<Option<Source>> {
+        let link = DB
+            .user_sources(user_id)?
+            .into_iter()
+            .find(|link| link.uri == uri.trim());
+        match link {
+            Some(link) => Ok(DB.source_cache(&link.uri)?.map(|source| Source {
+                tags: link.tags,
+                ..source
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_owned_by(uri: &str, user_id: u64) -> Result<bool> {
+        let links = DB.user_sources(user_id)?;
+        Ok(links.iter().any(|owned| owned.uri == uri.trim()))
+    }
+
+    /// Records that the account uses these sources, only writing when a new uri or new tags
+    /// show up. Links without tags keep the stored ones. Returns all of the account's links.
+    pub fn link_user(user_id: u64, links: Vec<LinkedSource>) -> Result<Vec<LinkedSource>> {
+        let links = links
+            .into_iter()
+            .map(|link| LinkedSource {
+                uri: link.uri.trim().to_string(),
+                tags: link.tags,
+            })
+            .filter(|link| !Self::is_local_url(&link.uri))
+            .collect::<Vec<_>>();
+
+        // most messages link nothing new and don't need to wait for a write transaction
+        let mut owned = DB.user_sources(user_id)?;
+        if !merge_links(&mut owned, &links) {
+            return Ok(owned);
+        }
+        DB.user_sources_update(user_id, |owned| merge_links(owned, &links))
+    }
+
+    /// Removes the source from the account. The cached record is shared between
+    /// accounts, so it is only deleted once nobody links it anymore.
+    pub fn unlink_user(user_id: u64, uri: &str) -> Result<()> {
+        DB.user_sources_unlink(user_id, uri.trim())?;
+        Ok(())
+    }
+
+    /// Fetches the source again regardless of its expiry.
+    /// A failed fetch keeps the previous chunks and only records the failure.
+    pub async fn refresh(
+        mut self,
+        credentials: Option<AccountCredentials>,
+        renderer: &RendererConfig,
+    ) -> Result<Self, SourceError> {
+        if self.uri.starts_with('_') {
+            return Err(SourceError::Default(eyre::eyre!(
+                "Raw content sources can't be refreshed"
+            )));
+        }
+
+        // the uri is scoped for authenticated sources, the chunks keep the plain url
+        let url: SerdeUrl = serde_json::from_str(format!("\"{}\"", self.chunks.url).as_str())
+            .map_err(|e| SourceError::Default(e.into()))?;
+
+        match Self::retrieve(url, self.expires, credentials.as_ref(), renderer).await {
+            Ok(source) => Ok(Self {
+                tags: self.tags,
+                ..source
+            }),
+            Err(e) => {
+                self.fetch_status = match &e {
+                    SourceError::ContentEmpty(_) => FetchStatus::Empty,
+                    SourceError::Default(e) => FetchStatus::Failed(e.to_string()),
+                };
+                self.save().map_err(SourceError::Default)?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn retrieve(
+        url: SerdeUrl,
+        expires: u32,
+        credentials: Option<&AccountCredentials>,
+        renderer: &RendererConfig,
+    ) -> Result<Self, SourceError> {
+        let content = Self::fetch(url.clone(), credentials, renderer.renderer().as_ref())
+            .await
+            .map_err(SourceError::Default)?;
+        if content.is_empty() {
+            return Err(SourceError::ContentEmpty(url.to_string()));
+        }
+
+        let source = Self {
+            // content: content.clone(),
+            uri: credentials
+                .map(|credentials| credentials.scope_uri(url.as_str()))
+                .unwrap_or_else(|| url.to_string()),
+            expires,
+            created_at: chrono::Utc::now().timestamp() as u32,
+            chunks: Chunks::new(content, url.as_str())
+                .await
+                .map_err(SourceError::Default)?,
+            fetch_status: FetchStatus::Ok,
+            tags: Tags::default(),
+        };
+        source.save().map_err(SourceError::Default)
+    }
+
+    pub(crate) fn parse_html(html: String) -> String {
         let selector_str: &str =
             "h1, h2, h3, h4, h5, h6, p, a, span, div, li, ul, ol, blockquote, pre, code";
 
@@ -155,38 +615,91 @@ impl Source {
             .expect("Unable to build reqwest client")
     }
 
-    async fn fetch(url: SerdeUrl) -> Result<String> {
+    /// With credentials, redirects are followed here so the credentials only go to the url's
+    /// own origin, reqwest would keep custom headers on a redirect to another host.
+    async fn get(url: &str, credentials: Option<&AccountCredentials>) -> Result<reqwest::Response> {
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => return Ok(Self::create_get_client().get(url).send().await?),
+        };
+
+        let client = reqwest::Client::builder()
+            .user_agent("PGBT")
+            .timeout(std::time::Duration::from_secs(30))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let mut url = reqwest::Url::parse(url)?;
+        let origin = url.origin();
+        for _ in 0..MAX_REDIRECTS {
+            let request = match url.origin() == origin {
+                true => credentials.apply(client.get(url.clone())),
+                false => client.get(url.clone()),
+            };
+            let resp = request.send().await?;
+
+            let location = resp
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok());
+            match location {
+                Some(location) if resp.status().is_redirection() => url = url.join(location)?,
+                _ => return Ok(resp),
+            }
+        }
+        Err(eyre::eyre!("Too many redirects fetching {}", url))
+    }
+
+    async fn fetch(
+        url: SerdeUrl,
+        credentials: Option<&AccountCredentials>,
+        renderer: &dyn Renderer,
+    ) -> Result<String> {
         let url = url.to_string();
-        let client = Self::create_get_client();
-        let resp = client.get(&url).send().await?;
+        let resp = Self::get(&url, credentials).await?;
         let remote_type: RemoteSourceType = resp.headers().get("content-type").into();
 
         let content = match remote_type {
-            RemoteSourceType::Pdf => {
-                let content_bytes = resp.bytes().await?;
-                pdf_extract::extract_text_from_mem(&content_bytes)?
-            }
             RemoteSourceType::Html => {
                 let body = resp.text().await?;
                 let content = Self::parse_html(body);
-                if content.len() < MIN_CONTENT_LENGTH {
+                // authenticated pages are never handed to the render service
+                if content.len() < MIN_CONTENT_LENGTH && credentials.is_none() {
                     //try server rendered version
-                    let scraper_api_url = format!(
-                        "http://api.scraperapi.com?api_key={}&url={}&render=true",
-                        dotenv!("SCRAPER_API_KEY"),
-                        url
-                    );
-
-                    let resp: reqwest::Response = client.get(scraper_api_url).send().await?;
-                    let body = resp.text().await?;
-
-                    Self::parse_html(body)
+                    match renderer.render(&url).await {
+                        Ok(Some(body)) => Self::parse_html(body),
+                        Ok(None) => content,
+                        Err(e) => {
+                            log::warn!("Failed to render {}, using the plain page: {}", url, e);
+                            content
+                        }
+                    }
                 } else {
                     content
                 }
             }
-            RemoteSourceType::DOCX => {
+            RemoteSourceType::Pdf | RemoteSourceType::DOCX => {
                 let content_bytes = resp.bytes().await?;
+                Self::extract(remote_type, &content_bytes)?
+            }
+            _ => resp.text().await?,
+        };
+
+        Ok(content)
+    }
+
+    /// Extracts the text out of a file by its path's extension, None if the format isn't supported.
+    pub fn extract_file(path: &str, content_bytes: &[u8]) -> Option<Result<String>> {
+        RemoteSourceType::from_path(path)
+            .map(|remote_type| Self::extract(remote_type, content_bytes))
+    }
+
+    fn extract(remote_type: RemoteSourceType, content_bytes: &[u8]) -> Result<String> {
+        let content = match remote_type {
+            RemoteSourceType::Pdf => pdf_extract::extract_text_from_mem(content_bytes)?,
+            RemoteSourceType::Html => {
+                Self::parse_html(String::from_utf8_lossy(content_bytes).into_owned())
+            }
+            RemoteSourceType::DOCX => {
                 let buffer = std::io::Cursor::new(content_bytes);
 
                 let docx_file = docx_rust::DocxFile::from_reader(buffer)
@@ -239,7 +752,9 @@ impl Source {
                 //merge iterator of strings into one string seperated by newlines
                 content.collect::<Vec<_>>().join("")
             }
-            _ => resp.text().await?,
+            RemoteSourceType::Json | RemoteSourceType::Text => {
+                String::from_utf8_lossy(content_bytes).into_owned()
+            }
         };
 
         Ok(content)
@@ -253,7 +768,14 @@ impl Source {
     //     vec![]
     // }
 
+    /// The source carries the input's tags, linking it records them for the account.
     pub async fn new(input: SourceInput) -> Result<(Self, bool), SourceError> {
+        let tags = input.tags.clone();
+        let (source, retrieved) = Self::cached_or_retrieved(input).await?;
+        Ok((Source { tags, ..source }, retrieved))
+    }
+
+    async fn cached_or_retrieved(input: SourceInput) -> Result<(Self, bool), SourceError> {
         let mut retrieved: bool = false;
 
         if input.content.is_none() && input.url.is_none() {
@@ -288,9 +810,15 @@ impl Source {
                             uri: content_hash,
                             expires: input.expires,
                             created_at: chrono::Utc::now().timestamp() as u32,
-                            chunks: Chunks::new(input.content.unwrap_or("".to_string()), "")
-                                .await
-                                .map_err(SourceError::Default)?,
+                            chunks: Chunks::with_chunking(
+                                input.content.unwrap_or("".to_string()),
+                                "",
+                                input.chunking,
+                            )
+                            .await
+                            .map_err(SourceError::Default)?,
+                            fetch_status: FetchStatus::Ok,
+                            tags: Tags::default(),
                         }
                         .save()
                         .map_err(SourceError::Default)?,
@@ -310,32 +838,53 @@ impl Source {
                     uri: input_url.to_string(),
                     expires: input.expires,
                     created_at: chrono::Utc::now().timestamp() as u32,
-                    chunks: Chunks::new(
+                    chunks: Chunks::with_chunking(
                         input.content.unwrap_or("".to_string()),
                         input_url.as_str(),
+                        input.chunking,
                     )
                     .await
                     .map_err(SourceError::Default)?,
+                    fetch_status: FetchStatus::Ok,
+                    tags: Tags::default(),
                     // ..Default::default()
                 },
                 retrieved,
             ));
         }
 
+        let uri = input.cache_key(input_url.as_str());
+
         let source = match input.content {
-            Some(content) => Source {
-                // content,
-                uri: input_url.to_string(),
-                expires: input.expires,
-                created_at: chrono::Utc::now().timestamp() as u32,
-                chunks: Chunks::new(content, input_url.as_str())
-                    .await
+            Some(content) => {
+                // provided content only needs new embeddings when it changed
+                let chunked_content = Chunks::split(&content, input.chunking);
+                let cached_source =
+                    Source::by_url(&uri)
+                        .map_err(SourceError::Default)?
+                        .filter(|source| {
+                            !source.is_expired() && source.chunks.value.0 == chunked_content
+                        });
+
+                match cached_source {
+                    Some(source) => source,
+                    _ => Source {
+                        // content,
+                        uri,
+                        expires: input.expires,
+                        created_at: chrono::Utc::now().timestamp() as u32,
+                        chunks: Chunks::with_chunking(content, input_url.as_str(), input.chunking)
+                            .await
+                            .map_err(SourceError::Default)?,
+                        fetch_status: FetchStatus::Ok,
+                        tags: Tags::default(),
+                    }
+                    .save()
                     .map_err(SourceError::Default)?,
+                }
             }
-            .save()
-            .map_err(SourceError::Default)?,
             _ => {
-                let cached_source = Source::by_url(input_url.as_str())
+                let cached_source = Source::by_url(&uri)
                     .map_err(SourceError::Default)?
                     .filter(|source| !source.is_expired());
 
@@ -343,23 +892,13 @@ impl Source {
                     Some(source) => source,
                     _ => {
                         retrieved = true; // we're retrieving this source
-                        let content = Self::fetch(input_url.clone())
-                            .await
-                            .map_err(SourceError::Default)?;
-                        if content.is_empty() {
-                            return Err(SourceError::ContentEmpty(input_url.to_string()));
-                        }
-
-                        let source = Self {
-                            // content: content.clone(),
-                            uri: input_url.to_string(),
-                            expires: input.expires,
-                            created_at: chrono::Utc::now().timestamp() as u32,
-                            chunks: Chunks::new(content, input_url.as_str())
-                                .await
-                                .map_err(SourceError::Default)?,
-                        };
-                        source.save().map_err(SourceError::Default)?
+                        Self::retrieve(
+                            input_url.clone(),
+                            input.expires,
+                            input.credentials.as_ref(),
+                            &input.renderer,
+                        )
+                        .await?
                     }
                 }
             }
@@ -368,6 +907,33 @@ impl Source {
         Ok((source, retrieved))
     }
 
+    /// Embeds the chunks again with the current model, keeping their text.
+    pub async fn reembed(mut self) -> Result<Self> {
+        self.chunks = self.chunks.reembed().await?;
+        if Self::is_local_url(&self.uri) {
+            return Ok(self);
+        }
+        self.save()
+    }
+
+    /// Re-embeds every cached source produced by another model, one at a time
+    /// so the pool keeps serving messages. Returns how many were migrated.
+    pub async fn reembed_stale() -> Result<usize> {
+        let mut migrated = 0;
+        for uri in DB.source_cache_uris()? {
+            let source = match Source::by_url(&uri)? {
+                Some(source) if !source.chunks.is_current() && !source.is_expired() => source,
+                _ => continue,
+            };
+
+            match source.reembed().await {
+                Ok(_) => migrated += 1,
+                Err(e) => log::error!("Failed to re-embed source {}: {}", uri, e),
+            }
+        }
+        Ok(migrated)
+    }
+
     pub fn is_expired(&self) -> bool {
         let expired = self.expires_timestamp() < chrono::Utc::now().timestamp() as u32;
         if expired {
@@ -385,6 +951,37 @@ impl Source {
     }
 }
 
+impl From<Source> for SourceOutput {
+    fn from(source: Source) -> Self {
+        let byte_size = source.chunks.value.0.iter().map(|chunk| chunk.len()).sum();
+
+        Self {
+            expires_at: source.expires_timestamp(),
+            chunk_count: source.chunks.value.0.len(),
+            byte_size,
+            uri: source.uri,
+            created_at: source.created_at,
+            fetch_status: source.fetch_status,
+            model: source.chunks.model,
+            tags: source.tags,
+        }
+    }
+}
+
+impl RemoteSourceType {
+    fn from_path(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+        match extension.as_str() {
+            "pdf" => Some(Self::Pdf),
+            "html" | "htm" => Some(Self::Html),
+            "json" => Some(Self::Json),
+            "md" | "markdown" | "mdx" | "txt" => Some(Self::Text),
+            "docx" => Some(Self::DOCX),
+            _ => None,
+        }
+    }
+}
+
 impl From<Option<&HeaderValue>> for RemoteSourceType {
     fn from(header: Option<&HeaderValue>) -> Self {
         match header {
@@ -412,16 +1009,29 @@ impl From<Option<&HeaderValue>> for RemoteSourceType {
 impl Chunks {
     const CHUNK_SIZE: usize = 10;
     pub async fn new(content: String, url: &str) -> Result<Self> {
+        Self::with_chunking(content, url, Chunking::Sentences).await
+    }
+
+    pub fn split(content: &str, chunking: Chunking) -> Vec<String> {
+        match chunking {
+            Chunking::Sentences => {
+                let unchunked_sentences = content.unicode_sentences().collect::<Vec<_>>();
+                //@todo: chunk by sentence length
+                unchunked_sentences
+                    .chunks(Self::CHUNK_SIZE)
+                    .map(|chunk| chunk.join(" "))
+                    .collect::<Vec<_>>()
+            }
+            Chunking::Whole => vec![content.to_string()],
+        }
+    }
+
+    pub async fn with_chunking(content: String, url: &str, chunking: Chunking) -> Result<Self> {
         if content.is_empty() {
             return Err(eyre::eyre!("Content is empty"));
         }
 
-        let unchunked_sentences = content.unicode_sentences().collect::<Vec<_>>();
-        //@todo: chunk by sentence length
-        let chunked_sentences = unchunked_sentences.chunks(Self::CHUNK_SIZE);
-        let chunked_sentences = chunked_sentences
-            .map(|chunk| chunk.join(" "))
-            .collect::<Vec<_>>();
+        let chunked_sentences = Self::split(&content, chunking);
 
         let _chunked_sentences = chunked_sentences.clone();
 
@@ -433,11 +1043,35 @@ impl Chunks {
         Ok(Self {
             url: url.to_string(),
             value: (chunked_sentences, embeddings),
+            model: EMBED_POOL.model_id(),
+            dimension: EMBED_POOL.dimension(),
+        })
+    }
+
+    pub fn is_current(&self) -> bool {
+        self.model == EMBED_POOL.model_id() && self.dimension == EMBED_POOL.dimension()
+    }
+
+    pub async fn reembed(self) -> Result<Self> {
+        let (sentences, _) = self.value;
+        let embeddings = EMBED_POOL
+            .encode(sentences.clone())
+            .await
+            .map_err(|_| eyre::eyre!("Failed to receive embeddings"))?;
+
+        Ok(Self {
+            url: self.url,
+            value: (sentences, embeddings),
+            model: EMBED_POOL.model_id(),
+            dimension: EMBED_POOL.dimension(),
         })
     }
 
     pub async fn query(query: String) -> Result<Vec<f32>> {
-        EMBED_POOL.encode(vec![query]).await.map(|e| e[0].clone())
+        EMBED_POOL
+            .encode_with_timeout(vec![query], EMBED_POOL.query_timeout)
+            .await
+            .map(|e| e[0].clone())
     }
 }
 
@@ -449,6 +1083,121 @@ impl Chunks {
 mod tests {
 
     use super::*;
+    use crate::{renderer::Disabled, types::credential::SourceCredentials};
+
+    // renders without leaving the machine
+    struct StandInRenderer;
+
+    #[async_trait::async_trait]
+    impl Renderer for StandInRenderer {
+        async fn render(&self, url: &str) -> Result<Option<String>> {
+            Ok(Some(format!(
+                "<p>Rendered {} {}</p>",
+                url,
+                "content ".repeat(20)
+            )))
+        }
+    }
+
+    struct FailingRenderer;
+
+    #[async_trait::async_trait]
+    impl Renderer for FailingRenderer {
+        async fn render(&self, _url: &str) -> Result<Option<String>> {
+            Err(eyre::eyre!("Quota exceeded"))
+        }
+    }
+
+    async fn serve_empty_page() -> SerdeUrl {
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(|| async { axum::response::Html("<div id=\"app\"></div>") }),
+        );
+        let address = serve(app).await;
+        serde_json::from_str(format!("\"http://{}/\"", address).as_str()).expect("url")
+    }
+
+    async fn serve(app: axum::Router) -> std::net::SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("listener");
+        let address = listener.local_addr().expect("address");
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .expect("server")
+                .serve(app.into_make_service()),
+        );
+        address
+    }
+
+    #[tokio::test]
+    async fn credentials_stay_on_origin() {
+        let echo = || {
+            axum::routing::get(|headers: axum::http::HeaderMap| async move {
+                headers
+                    .get("x-api-key")
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("none")
+                    .to_string()
+            })
+        };
+        let other = serve(axum::Router::new().route("/echo", echo())).await;
+        let redirect = |location: String| {
+            axum::routing::get(
+                move || async move { axum::response::Redirect::temporary(&location) },
+            )
+        };
+        let origin = serve(
+            axum::Router::new()
+                .route("/echo", echo())
+                .route("/moved", redirect("/echo".to_string()))
+                // another port is another origin
+                .route("/away", redirect(format!("http://{}/echo", other))),
+        )
+        .await;
+
+        let credentials = AccountCredentials {
+            user_id: 1,
+            credentials: SourceCredentials {
+                headers: vec![("x-api-key".to_string(), "secret".to_string())],
+                ..Default::default()
+            },
+        };
+        let fetch = |path: &str| {
+            let url = format!("http://{}{}", origin, path);
+            let credentials = credentials.clone();
+            async move {
+                Source::get(&url, Some(&credentials))
+                    .await
+                    .expect("response")
+                    .text()
+                    .await
+                    .expect("body")
+            }
+        };
+
+        assert_eq!(fetch("/moved").await, "secret");
+        assert_eq!(fetch("/away").await, "none");
+    }
+
+    #[tokio::test]
+    async fn render_fallback() {
+        let url = serve_empty_page().await;
+
+        let content = Source::fetch(url.clone(), None, &StandInRenderer)
+            .await
+            .expect("content");
+        assert!(content.contains("Rendered"));
+
+        let content = Source::fetch(url.clone(), None, &Disabled)
+            .await
+            .expect("content");
+        assert!(!content.contains("Rendered"));
+
+        // a failing renderer leaves the plain page
+        let content = Source::fetch(url, None, &FailingRenderer)
+            .await
+            .expect("content");
+        assert!(!content.contains("Rendered"));
+    }
 
     #[test]
     fn local_url() {
@@ -464,6 +1213,7 @@ mod tests {
             content: None,
             url: None,
             expires: 86400,
+            ..Default::default()
         };
         assert!(Source::new(input).await.is_err());
     }
@@ -474,6 +1224,7 @@ mod tests {
             content: Some("Hello world".to_string()),
             url: None,
             expires: 86400,
+            ..Default::default()
         };
         assert!(Source::new(input).await.is_ok());
     }
@@ -485,6 +1236,7 @@ mod tests {
             content: None,
             url: Some(google_url),
             expires: 86400,
+            ..Default::default()
         };
         assert!(Source::new(input).await.is_ok());
     }
@@ -498,6 +1250,7 @@ mod tests {
             content: None,
             url: Some(nextui_url),
             expires: 86400,
+            ..Default::default()
         };
         let (source, _) = Source::new(input).await.expect("source");
 
@@ -509,14 +1262,18 @@ mod tests {
         let w3 = "https://www.w3.org/WAI/ER/tests/xhtml/testfiles/resources/pdf/dummy.pdf";
         let pdf_url: SerdeUrl = serde_json::from_str(format!("\"{}\"", w3).as_str()).expect("url");
 
-        let source = Source::fetch(pdf_url).await.expect("source");
+        let source = Source::fetch(pdf_url, None, &Disabled)
+            .await
+            .expect("source");
 
         assert!(source.contains("Dummy"));
 
         let pdf_2 = "https://www.africau.edu/images/default/sample.pdf";
         let pdf_url: SerdeUrl =
             serde_json::from_str(format!("\"{}\"", pdf_2).as_str()).expect("url");
-        let source = Source::fetch(pdf_url).await.expect("source");
+        let source = Source::fetch(pdf_url, None, &Disabled)
+            .await
+            .expect("source");
 
         assert!(source.contains("Simple"))
     }
@@ -527,7 +1284,7 @@ mod tests {
         let st = "https://api.arible.co/user_admin/shiro.nohara111@gmail.com?auth_token=eyJhbGciOiJIUzI1NiJ9.eyJhdWQiOiJhdXRoZW50aWNhdGVkIiwiZXhwIjoxNzgxMjg2NjAxLCJzdWIiOiI2ZGYwZmMwMC1hZWFjLTQyMmItODllNi1jOWNkNjkxNTZkYjciLCJlbWFpbCI6ImNoaXNpbWRpcmkuZWppbmtlb255ZUBnbWFpbC5jb20iLCJwaG9uZSI6IiIsImFwcF9tZXRhZGF0YSI6eyJwcm92aWRlciI6ImVtYWlsIiwicHJvdmlkZXJzIjpbImVtYWlsIiwiZ29vZ2xlIl19LCJ1c2VyX21ldGFkYXRhIjp7ImF2YXRhcl91cmwiOiJodHRwczovL2xoMy5nb29nbGV1c2VyY29udGVudC5jb20vYS9BR05teXhZWENpWUJFRXlETWFabm1FdVNlSW5ja0cwajE1THRfN0cyTTRoaT1zOTYtYyIsImVtYWlsIjoiY2hpc2ltZGlyaS5lamlua2VvbnllQGdtYWlsLmNvbSIsImVtYWlsX3ZlcmlmaWVkIjp0cnVlLCJmdWxsX25hbWUiOiJDaGlzaW1kaXJpIEVqaW5rZW9ueWUiLCJpc3MiOiJodHRwczovL3d3dy5nb29nbGVhcGlzLmNvbS91c2VyaW5mby92Mi9tZSIsIm5hbWUiOiJDaGlzaW1kaXJpIEVqaW5rZW9ueWUiLCJwaWN0dXJlIjoiaHR0cHM6Ly9saDMuZ29vZ2xldXNlcmNvbnRlbnQuY29tL2EvQUdObXl4WVhDaVlCRUV5RE1hWm5tRXVTZUluY2tHMGoxNUx0XzdHMk00aGk9czk2LWMiLCJwcm92aWRlcl9pZCI6IjEwOTUyNDg4MDQzODEwMTMxMjE5NSIsInN1YiI6IjEwOTUyNDg4MDQzODEwMTMxMjE5NSJ9LCJyb2xlIjoiYXV0aGVudGljYXRlZCIsImFhbCI6ImFhbDEiLCJhbXIiOlt7Im1ldGhvZCI6Im9hdXRoIiwidGltZXN0YW1wIjoxNjgxMjY0MzE2fV0sInNlc3Npb25faWQiOiIzYTUyZDFjZi00MDE1LTRlOTAtOTEyZS1iYzZkMTFhZDZlMWUifQ.nF5OFgwR4DPV8nXJ2iQ4uWhLFCkHfZNquwqbwVX5y84";
         let url: SerdeUrl = serde_json::from_str(format!("\"{}\"", st).as_str()).expect("url");
 
-        let source = Source::fetch(url).await.expect("source");
+        let source = Source::fetch(url, None, &Disabled).await.expect("source");
         assert!(source.contains("credits"));
     }
 
@@ -538,6 +1295,7 @@ mod tests {
             content: Some("Hello world".to_string()),
             url: None,
             expires: 86400,
+            ..Default::default()
         };
         let (source, _) = Source::new(input).await.expect("source");
         assert_eq!(source.content(), "Hello world");
@@ -549,6 +1307,7 @@ mod tests {
             content: None,
             url: Some(serde_json::from_str("\"https://www.arible.co/sitemap.xml\"").expect("url")),
             expires: 86400,
+            ..Default::default()
         };
         let source_inputs = input
             .clone()
@@ -572,6 +1331,112 @@ mod tests {
         assert!(input.is_sitemap());
     }
 
+    #[test]
+    fn source_output() {
+        let source = Source {
+            uri: "https://thepagebot.com".to_string(),
+            expires: 100,
+            created_at: 1000,
+            chunks: Chunks {
+                url: "https://thepagebot.com".to_string(),
+                value: (
+                    vec!["Hello".to_string(), "world".to_string()],
+                    vec![vec![0.0], vec![1.0]],
+                ),
+                model: "fake/1".to_string(),
+                dimension: 1,
+            },
+            fetch_status: FetchStatus::Ok,
+            tags: Tags::from([("product".to_string(), "pagebot".to_string())]),
+        };
+
+        let output = SourceOutput::from(source);
+        assert_eq!(output.expires_at, 1100);
+        assert_eq!(output.chunk_count, 2);
+        assert_eq!(output.byte_size, 10);
+        assert_eq!(output.fetch_status, FetchStatus::Ok);
+        assert_eq!(output.model, "fake/1");
+        assert_eq!(output.tags["product"], "pagebot");
+    }
+
+    #[test]
+    fn tag_filter() {
+        let tags = Tags::from([
+            ("product".to_string(), "a".to_string()),
+            ("language".to_string(), "en".to_string()),
+        ]);
+        let filter = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Tags>()
+        };
+
+        assert!(matches_tags(&tags, &filter(&[("product", "a")])));
+        assert!(!matches_tags(&tags, &filter(&[("product", "b")])));
+        // untagged keys don't rule a source out
+        assert!(matches_tags(&tags, &filter(&[("version", "2")])));
+        assert!(matches_tags(&Tags::new(), &filter(&[("product", "b")])));
+        assert!(matches_tags(&tags, &Tags::new()));
+    }
+
+    #[test]
+    fn legacy_links() {
+        let links: Vec<LinkedSource> = serde_json::from_str(
+            r#"["https://thepagebot.com", {"uri": "https://thepagebot.com/docs", "tags": {"product": "a"}}]"#,
+        )
+        .expect("links");
+        assert_eq!(links[0].uri, "https://thepagebot.com");
+        assert!(links[0].tags.is_empty());
+        assert_eq!(links[1].tags["product"], "a");
+
+        let stored = serde_json::to_string(&links[1]).expect("json");
+        let link: LinkedSource = serde_json::from_str(&stored).expect("link");
+        assert_eq!(link, links[1]);
+    }
+
+    #[test]
+    fn merge_account_links() {
+        let link = |uri: &str, tags: &[(&str, &str)]| LinkedSource {
+            uri: uri.to_string(),
+            tags: tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+        let mut owned = vec![link("https://thepagebot.com/docs", &[("product", "a")])];
+
+        // untagged links keep the stored tags
+        assert!(!merge_links(
+            &mut owned,
+            &[link("https://thepagebot.com/docs", &[])]
+        ));
+        assert!(merge_links(
+            &mut owned,
+            &[
+                link("https://thepagebot.com/docs", &[("product", "b")]),
+                link("https://thepagebot.com/blog", &[]),
+            ]
+        ));
+        assert_eq!(
+            owned,
+            vec![
+                link("https://thepagebot.com/docs", &[("product", "b")]),
+                link("https://thepagebot.com/blog", &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_chunks_model() {
+        let chunks: Chunks =
+            serde_json::from_str(r#"{"url":"","value":[["Hello"],[[1.0]]]}"#).expect("chunks");
+        assert_eq!(chunks.model, "rust-bert/all-mini-lm-l6-v2");
+        assert_eq!(chunks.dimension, 384);
+        // tests embed with the fake embedder
+        assert!(!chunks.is_current());
+    }
+
     #[test]
     fn sitemap_formats() {
         let is_sitemap = vec![
@@ -583,6 +1448,7 @@ mod tests {
             content: None,
             url: Some(serde_json::from_str(format!("\"{}\"", url).as_str()).expect("url")),
             expires: 86400,
+            ..Default::default()
         })
         .all(|input| input.is_sitemap());
 
@@ -592,10 +1458,64 @@ mod tests {
                 content: None,
                 url: Some(serde_json::from_str(format!("\"{}\"", url).as_str()).expect("url")),
                 expires: 86400,
+                ..Default::default()
             })
             .all(|input| !input.is_sitemap());
 
         assert!(is_not_sitemap);
         assert!(is_sitemap);
     }
+
+    #[test]
+    fn source_kinds() {
+        let kind = |url: &str| {
+            SourceInput {
+                url: Some(serde_json::from_str(format!("\"{}\"", url).as_str()).expect("url")),
+                ..Default::default()
+            }
+            .kind()
+        };
+
+        assert_eq!(kind("https://www.arible.co/feed"), SourceKind::Feed);
+        assert_eq!(kind("https://www.arible.co/blog/rss.xml"), SourceKind::Feed);
+        assert_eq!(kind("https://www.arible.co/atom.xml"), SourceKind::Feed);
+        assert_eq!(
+            kind("https://api.arible.co/openapi.json"),
+            SourceKind::OpenApi
+        );
+        assert_eq!(
+            kind("https://api.arible.co/swagger.yaml"),
+            SourceKind::OpenApi
+        );
+        assert_eq!(
+            kind("https://www.arible.co/sitemap.xml"),
+            SourceKind::Sitemap
+        );
+        assert_eq!(kind("https://www.arible.co/feedback"), SourceKind::Page);
+
+        let explicit = SourceInput {
+            url: Some(serde_json::from_str("\"https://www.arible.co/updates\"").expect("url")),
+            kind: Some(SourceKind::Feed),
+            ..Default::default()
+        };
+        assert_eq!(explicit.kind(), SourceKind::Feed);
+    }
+
+    #[tokio::test]
+    async fn expansions_need_url() {
+        for kind in [SourceKind::Feed, SourceKind::OpenApi, SourceKind::Sitemap] {
+            let input = SourceInput {
+                content: Some("<rss></rss>".to_string()),
+                kind: Some(kind),
+                ..Default::default()
+            };
+            assert!(input.process().await.is_err());
+        }
+    }
+
+    #[test]
+    fn whole_chunking() {
+        let content = "First sentence. Second sentence.";
+        assert_eq!(Chunks::split(content, Chunking::Whole), vec![content]);
+    }
 }
@@ -2,9 +2,16 @@ use eyre::Result;
 use serde::{Deserialize, Serialize};
 use serde_email::Email;
 
-use crate::db::DB;
+use crate::{
+    chat_provider::{ChatSettings, ChatSettingsOutput},
+    db::DB,
+    renderer::RendererConfig,
+};
 
-use super::usage::{Usage, UsageOutput};
+use super::{
+    page_rule::PageRule,
+    usage::{Usage, UsageOutput},
+};
 
 pub const FREE_MESSAGE_COUNT: u32 = 50;
 
@@ -18,6 +25,15 @@ pub struct User {
     pub current_limit: u32,
     pub ls_subscription_id: Option<u64>,
     pub allowed_domains: Option<Vec<String>>,
+    #[serde(default)]
+    pub renderer: RendererConfig,
+    // best similarity under which a question is answered as not found, set by `calibrate`
+    #[serde(default)]
+    pub not_found_threshold: Option<f32>,
+    #[serde(default)]
+    pub page_rules: Vec<PageRule>,
+    #[serde(default)]
+    pub chat: ChatSettings,
 }
 
 pub struct UserInput {
@@ -33,6 +49,10 @@ pub struct UserOutput {
     pub usage: UsageOutput,
     pub subscription_id: Option<u64>,
     pub allowed_domains: Option<Vec<String>>,
+    pub renderer: RendererConfig,
+    pub not_found_threshold: Option<f32>,
+    pub page_rules: Vec<PageRule>,
+    pub chat: ChatSettingsOutput,
 }
 
 impl UserInput {
@@ -76,6 +96,10 @@ impl From<UserInput> for User {
             current_limit: FREE_MESSAGE_COUNT,
             ls_subscription_id: None,
             allowed_domains: None,
+            renderer: RendererConfig::default(),
+            not_found_threshold: None,
+            page_rules: vec![],
+            chat: ChatSettings::default(),
         }
     }
 }
@@ -94,6 +118,10 @@ impl From<User> for UserOutput {
             subscription_id: user.ls_subscription_id,
             usage,
             allowed_domains: user.allowed_domains,
+            renderer: user.renderer,
+            not_found_threshold: user.not_found_threshold,
+            page_rules: user.page_rules,
+            chat: user.chat.into(),
         }
     }
 }
@@ -0,0 +1,506 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use eyre::Result;
+use hnsw_rs::{
+    hnswio::{load_description, load_hnsw},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::DB,
+    embed_pool::{Embedding, EMBED_POOL},
+    types::source::Source,
+};
+
+/// Below this many vectors exact search beats building or querying an index.
+pub const EXACT_SEARCH_LIMIT: usize = 2000;
+// share of replaced points after which the graph is rebuilt from the live ones
+const MAX_STALE_RATIO: f32 = 0.3;
+// syncs within this window are written by a single dump
+const PERSIST_DELAY: Duration = Duration::from_secs(60);
+
+/// What the points of an account's graph refer to, stored in the db next to the graph files.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct IndexManifest {
+    pub model: String,
+    // point id -> (uri, chunk), None once its source was replaced
+    pub points: Vec<Option<(String, usize)>>,
+    // uri -> created_at of the indexed version
+    pub versions: HashMap<String, u32>,
+    // names the graph files this manifest belongs to, 0 for the ones dumped before
+    #[serde(default)]
+    pub generation: u64,
+}
+
+/// An account's HNSW graph, updated in place as its sources change.
+pub struct AccountIndex {
+    manifest: IndexManifest,
+    hnsw: Hnsw<f32, DistCosine>,
+    stale_count: usize,
+    rebuilding: bool,
+    persist_pending: bool,
+}
+
+lazy_static! {
+    static ref INDEXES: Mutex<HashMap<u64, Arc<RwLock<AccountIndex>>>> = Mutex::new(HashMap::new());
+    // one dump at a time, so a generation's files are never removed while still in use
+    static ref PERSISTING: Mutex<()> = Mutex::new(());
+}
+
+fn new_hnsw(capacity: usize) -> Hnsw<f32, DistCosine> {
+    Hnsw::new(90, capacity.max(1), 16, 50, DistCosine)
+}
+
+fn index_path(user_id: u64, generation: u64) -> PathBuf {
+    let name = match generation {
+        0 => user_id.to_string(),
+        generation => format!("{}.{}", user_id, generation),
+    };
+    PathBuf::from("database").join("index").join(name)
+}
+
+fn remove_dump(path: &Path) {
+    let path = path.to_string_lossy();
+    for file in [
+        format!("{}.hnsw.graph", path),
+        format!("{}.hnsw.data", path),
+    ] {
+        _ = fs::remove_file(file);
+    }
+}
+
+impl AccountIndex {
+    pub fn empty() -> Self {
+        Self {
+            manifest: IndexManifest {
+                model: EMBED_POOL.model_id(),
+                ..Default::default()
+            },
+            hnsw: new_hnsw(EXACT_SEARCH_LIMIT),
+            stale_count: 0,
+            rebuilding: false,
+            persist_pending: false,
+        }
+    }
+
+    /// Reads the graph dumped by `persist`, None if there is none for the current model.
+    fn load(user_id: u64) -> Result<Option<Self>> {
+        let manifest = match DB.vector_index(user_id)? {
+            Some(manifest) if manifest.model == EMBED_POOL.model_id() => manifest,
+            _ => return Ok(None),
+        };
+
+        let path = index_path(user_id, manifest.generation)
+            .to_string_lossy()
+            .to_string();
+        let (graph, data) = match (
+            File::open(format!("{}.hnsw.graph", path)),
+            File::open(format!("{}.hnsw.data", path)),
+        ) {
+            (Ok(graph), Ok(data)) => (graph, data),
+            _ => return Ok(None),
+        };
+
+        let mut graph = BufReader::new(graph);
+        let mut data = BufReader::new(data);
+        let description = load_description(&mut graph).map_err(|e| eyre::eyre!("{:?}", e))?;
+        let hnsw = load_hnsw::<f32, DistCosine>(&mut graph, &description, &mut data)
+            .map_err(|e| eyre::eyre!("{:?}", e))?;
+
+        Ok(Some(Self {
+            stale_count: manifest
+                .points
+                .iter()
+                .filter(|point| point.is_none())
+                .count(),
+            manifest,
+            hnsw,
+            rebuilding: false,
+            persist_pending: false,
+        }))
+    }
+
+    /// Dumps the graph to files of a new generation and only then points the manifest at them,
+    /// so a crash mid-dump leaves the previous graph in place.
+    fn persist(&self, user_id: u64) -> Result<()> {
+        let _persisting = PERSISTING.lock().unwrap();
+        let generation = (chrono::Utc::now().timestamp_nanos() as u64).max(1);
+        let path = index_path(user_id, generation);
+        let temp_path = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if let Err(e) = self
+            .hnsw
+            .file_dump(&temp_path.to_string_lossy().to_string())
+        {
+            remove_dump(&temp_path);
+            return Err(eyre::eyre!("Failed to dump index: {:?}", e));
+        }
+        for extension in ["hnsw.graph", "hnsw.data"] {
+            fs::rename(
+                format!("{}.{}", temp_path.to_string_lossy(), extension),
+                format!("{}.{}", path.to_string_lossy(), extension),
+            )?;
+        }
+
+        let previous = DB.vector_index(user_id)?;
+        DB.vector_index_save(
+            user_id,
+            IndexManifest {
+                generation,
+                ..self.manifest.clone()
+            },
+        )?;
+        if let Some(previous) = previous {
+            remove_dump(&index_path(user_id, previous.generation));
+        }
+        Ok(())
+    }
+
+    fn is_synced(&self, sources: &[Source]) -> bool {
+        sources
+            .iter()
+            .all(|source| self.manifest.versions.get(&source.uri) == Some(&source.created_at))
+    }
+
+    /// Replaces the points of every source whose indexed version differs and marks those of
+    /// sources the account no longer links as stale.
+    pub fn sync(&mut self, sources: &[Source], linked: &HashSet<String>) {
+        let mut stale_uris = self
+            .manifest
+            .versions
+            .keys()
+            .filter(|uri| !linked.contains(*uri))
+            .cloned()
+            .collect::<HashSet<_>>();
+        let changed = sources
+            .iter()
+            .filter(|source| self.manifest.versions.get(&source.uri) != Some(&source.created_at))
+            .collect::<Vec<_>>();
+        stale_uris.extend(
+            changed
+                .iter()
+                .filter(|source| self.manifest.versions.contains_key(&source.uri))
+                .map(|source| source.uri.clone()),
+        );
+
+        if !stale_uris.is_empty() {
+            for point in self.manifest.points.iter_mut() {
+                if matches!(point, Some((uri, _)) if stale_uris.contains(uri.as_str())) {
+                    *point = None;
+                    self.stale_count += 1;
+                }
+            }
+            self.manifest
+                .versions
+                .retain(|uri, _| !stale_uris.contains(uri));
+        }
+
+        for source in changed {
+            self.insert(source);
+        }
+    }
+
+    fn insert(&mut self, source: &Source) {
+        let first_id = self.manifest.points.len();
+        let embeddings = &source.chunks.value.1;
+        let points = embeddings
+            .iter()
+            .enumerate()
+            .map(|(chunk, embedding)| (embedding, first_id + chunk))
+            .collect::<Vec<(&Embedding, usize)>>();
+        self.hnsw.parallel_insert(&points);
+
+        self.manifest
+            .points
+            .extend((0..embeddings.len()).map(|chunk| Some((source.uri.clone(), chunk))));
+        self.manifest
+            .versions
+            .insert(source.uri.clone(), source.created_at);
+    }
+
+    fn needs_rebuild(&self) -> bool {
+        self.stale_count as f32 > self.manifest.points.len() as f32 * MAX_STALE_RATIO
+    }
+
+    /// Builds a fresh graph from the latest version of each of these sources.
+    fn rebuild(uris: &[String]) -> Self {
+        let mut index = Self::empty();
+        for uri in uris {
+            if let Ok(Some(source)) = Source::by_url(uri) {
+                if source.chunks.is_current() {
+                    index.insert(&source);
+                }
+            }
+        }
+        index
+    }
+
+    /// Flat indexes into the sources' chunks, in order, of the closest chunks to the query
+    /// along with their cosine similarity. Points of other sources of the account are skipped.
+    pub fn search(&self, sources: &[Source], query: &[f32], kn: usize) -> Vec<(usize, f32)> {
+        let mut offsets = HashMap::new();
+        let mut offset = 0;
+        for source in sources {
+            offsets.insert(source.uri.as_str(), offset);
+            offset += source.chunks.value.1.len();
+        }
+
+        // stale points and the account's other sources are skipped while walking the graph
+        let filter = |id: &usize| {
+            matches!(
+                self.manifest.points.get(*id),
+                Some(Some((uri, _))) if offsets.contains_key(uri.as_str())
+            )
+        };
+        self.hnsw
+            .search_filter(query, kn, kn.max(60), Some(&filter))
+            .into_iter()
+            .filter_map(|neighbour| match self.manifest.points.get(neighbour.d_id) {
+                Some(Some((uri, chunk))) => offsets
+                    .get(uri.as_str())
+                    .map(|offset| (offset + chunk, 1.0 - neighbour.distance)),
+                _ => None,
+            })
+            .take(kn)
+            .collect()
+    }
+}
+
+/// Reads every persisted index, meant to run once at startup before messages are served.
+/// Returns how many were loaded.
+pub fn load_indexes() -> Result<usize> {
+    let mut loaded = 0;
+    for user_id in DB.vector_index_user_ids()? {
+        match AccountIndex::load(user_id) {
+            Ok(Some(index)) => {
+                INDEXES
+                    .lock()
+                    .unwrap()
+                    .insert(user_id, Arc::new(RwLock::new(index)));
+                loaded += 1;
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to load index of {}: {}", user_id, e),
+        }
+    }
+    Ok(loaded)
+}
+
+fn account_index(user_id: u64) -> Arc<RwLock<AccountIndex>> {
+    INDEXES
+        .lock()
+        .unwrap()
+        .entry(user_id)
+        .or_insert_with(|| Arc::new(RwLock::new(AccountIndex::empty())))
+        .clone()
+}
+
+/// Dumps the index once `PERSIST_DELAY` has passed, syncs until then are part of the same dump.
+fn persist_in_background(user_id: u64, shared_index: Arc<RwLock<AccountIndex>>) {
+    {
+        let mut index = shared_index.write().unwrap();
+        if index.persist_pending {
+            return;
+        }
+        index.persist_pending = true;
+    }
+
+    tokio::spawn(async move {
+        tokio::time::sleep(PERSIST_DELAY).await;
+        _ = tokio::task::spawn_blocking(move || {
+            // later syncs schedule another dump
+            shared_index.write().unwrap().persist_pending = false;
+            _ = shared_index
+                .read()
+                .unwrap()
+                .persist(user_id)
+                .map_err(|e| log::error!("Failed to persist index of {}: {}", user_id, e));
+        })
+        .await;
+    });
+}
+
+/// Swaps in a graph rebuilt from the db, away from the request and without holding the lock
+/// while reading. Updates synced in the meantime are picked up again by the next search.
+fn rebuild_in_background(user_id: u64, shared_index: Arc<RwLock<AccountIndex>>) {
+    let uris = {
+        let mut index = shared_index.write().unwrap();
+        if index.rebuilding {
+            return;
+        }
+        index.rebuilding = true;
+        index.manifest.versions.keys().cloned().collect::<Vec<_>>()
+    };
+
+    tokio::task::spawn_blocking(move || {
+        let rebuilt = AccountIndex::rebuild(&uris);
+        *shared_index.write().unwrap() = rebuilt;
+        _ = shared_index
+            .read()
+            .unwrap()
+            .persist(user_id)
+            .map_err(|e| log::error!("Failed to persist index of {}: {}", user_id, e));
+    });
+}
+
+/// Indexes of the kn chunks closest to the query, counting chunks across the sources in order,
+/// with their cosine similarity. Small sets are searched exactly, larger ones through the
+/// account's persistent index.
+pub fn top_similar(
+    user_id: u64,
+    sources: &[Source],
+    query: &[f32],
+    kn: usize,
+) -> Vec<(usize, f32)> {
+    let embeddings = sources
+        .iter()
+        .flat_map(|source| source.chunks.value.1.iter())
+        .collect::<Vec<_>>();
+    if embeddings.len() <= EXACT_SEARCH_LIMIT {
+        return exact_top_similar(&embeddings, query, kn);
+    }
+
+    let shared_index = account_index(user_id);
+    let synced = shared_index.read().unwrap().is_synced(sources);
+    if !synced {
+        let mut linked = match DB.user_sources(user_id) {
+            Ok(links) => links
+                .into_iter()
+                .map(|link| link.uri)
+                .collect::<HashSet<_>>(),
+            Err(e) => {
+                log::error!("Failed to get user sources of {}: {}", user_id, e);
+                return exact_top_similar(&embeddings, query, kn);
+            }
+        };
+        linked.extend(sources.iter().map(|source| source.uri.clone()));
+
+        let needs_rebuild = {
+            let mut index = shared_index.write().unwrap();
+            index.sync(sources, &linked);
+            index.needs_rebuild()
+        };
+
+        match needs_rebuild {
+            true => rebuild_in_background(user_id, Arc::clone(&shared_index)),
+            false => persist_in_background(user_id, Arc::clone(&shared_index)),
+        }
+    }
+
+    let similar = shared_index.read().unwrap().search(sources, query, kn);
+    // the account's other sources crowded out this message's, search them exactly instead
+    if similar.len() < kn.min(embeddings.len()) {
+        return exact_top_similar(&embeddings, query, kn);
+    }
+    similar
+}
+
+pub fn exact_top_similar(embeddings: &[&Embedding], query: &[f32], kn: usize) -> Vec<(usize, f32)> {
+    let mut similarities = embeddings
+        .iter()
+        .enumerate()
+        .map(|(i, embedding)| (i, 1.0 - DistCosine.eval(embedding.as_slice(), query)))
+        .collect::<Vec<_>>();
+    similarities.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    similarities.truncate(kn);
+    similarities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::source::{Chunks, FetchStatus};
+
+    fn source(uri: &str, created_at: u32, embeddings: Vec<Embedding>) -> Source {
+        Source {
+            uri: uri.to_string(),
+            expires: 100,
+            created_at,
+            chunks: Chunks {
+                url: uri.to_string(),
+                value: (
+                    embeddings.iter().map(|_| "chunk".to_string()).collect(),
+                    embeddings,
+                ),
+                model: EMBED_POOL.model_id(),
+                dimension: 2,
+            },
+            fetch_status: FetchStatus::Ok,
+            tags: Default::default(),
+        }
+    }
+
+    fn indexes(hits: Vec<(usize, f32)>) -> Vec<usize> {
+        hits.into_iter().map(|(index, _)| index).collect()
+    }
+
+    #[test]
+    fn exact_search() {
+        let embeddings = vec![vec![0.0, 1.0], vec![1.0, 0.1], vec![1.0, 0.0]];
+        let embeddings = embeddings.iter().collect::<Vec<_>>();
+        let hits = exact_top_similar(&embeddings, &[1.0, 0.0], 2);
+        assert_eq!(indexes(hits.clone()), vec![2, 1]);
+        assert!((hits[0].1 - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn index_sync_and_search() {
+        let docs = source(
+            "https://thepagebot.com/docs",
+            1,
+            vec![vec![0.0, 1.0], vec![1.0, 0.0]],
+        );
+        let blog = source("https://thepagebot.com/blog", 1, vec![vec![0.9, 0.1]]);
+
+        let linked = |sources: &[&Source]| {
+            sources
+                .iter()
+                .map(|source| source.uri.clone())
+                .collect::<HashSet<_>>()
+        };
+
+        let mut index = AccountIndex::empty();
+        index.sync(&[docs.clone(), blog.clone()], &linked(&[&docs, &blog]));
+        assert!(index.is_synced(&[docs.clone(), blog.clone()]));
+
+        // only the message's own sources are returned, offsets follow their order
+        assert_eq!(
+            indexes(index.search(&[blog.clone(), docs.clone()], &[1.0, 0.0], 1)),
+            vec![2]
+        );
+        assert_eq!(
+            indexes(index.search(&[blog.clone()], &[1.0, 0.0], 1)),
+            vec![0]
+        );
+        assert_eq!(
+            indexes(index.search(&[docs.clone()], &[1.0, 0.0], 1)),
+            vec![1]
+        );
+
+        let docs = source("https://thepagebot.com/docs", 2, vec![vec![1.0, 0.0]]);
+        assert!(!index.is_synced(&[docs.clone()]));
+        index.sync(&[docs.clone()], &linked(&[&docs, &blog]));
+        assert_eq!(index.stale_count, 2);
+        assert_eq!(
+            indexes(index.search(&[docs.clone()], &[1.0, 0.0], 2)),
+            vec![0]
+        );
+
+        // the blog was unlinked, its point goes stale without a new version of it
+        index.sync(&[docs.clone()], &linked(&[&docs]));
+        assert_eq!(index.stale_count, 3);
+        assert!(!index.manifest.versions.contains_key(&blog.uri));
+        assert!(index.search(&[blog], &[1.0, 0.0], 1).is_empty());
+    }
+}